@@ -0,0 +1,376 @@
+use crate::bundle::{build_bundle, check_bundle_options, create_dist_dir, list_files, FileListing};
+use crate::cli::{AppPackaging, BuildArgs};
+use crate::config::{Config, HttpConfig};
+use crate::download::contrib_dir;
+use crate::entry::Entry;
+use crate::errors::{BundlerResult, Error};
+use crate::events::{emit, json_output, millis, Event, FileKind};
+use crate::html::{write_index_html_file, write_landing_page, HtmlContext};
+use crate::http::HttpClient;
+use crate::js::{write_javascript, JsContext};
+use crate::library::write_library_image;
+use crate::manifest::BundleManifest;
+use crate::renv::RenvLock;
+use crate::report::SizeReport;
+use crate::repository::{open_repository, PackageRepository};
+use crate::size::format_size;
+use crate::webr::{
+    check_compatibility, check_mount_support, local_version, vendor_webr, WebRSource,
+};
+use colored::Colorize;
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Directory of a multi-app bundle holding one subdirectory per app.
+pub const APPS_DIR: &str = "apps";
+
+/// An app being bundled and where its files go.
+struct AppTarget {
+    name: String,
+    appdir: PathBuf,
+    /// Directory of the app's page, relative to the root of the bundle.
+    /// `None` when the bundle has a single app living at the root.
+    path: Option<String>,
+    outdir: PathBuf,
+    config: Config,
+    entry: Entry,
+    html: HtmlContext,
+}
+
+fn app_name(appdir: &Path) -> BundlerResult<String> {
+    let appdir = appdir.canonicalize().map_err(Error::bundle(appdir))?;
+    Ok(appdir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "app".to_string()))
+}
+
+fn app_targets(build_args: &BuildArgs) -> BundlerResult<Vec<AppTarget>> {
+    let appdirs = build_args.appdirs();
+    let multi_app = appdirs.len() > 1;
+    let mut names = BTreeSet::new();
+    let mut targets = Vec::with_capacity(appdirs.len());
+    for appdir in appdirs {
+        let name = app_name(appdir)?;
+        if !names.insert(name.clone()) {
+            return Err(Error::DuplicateAppName(name));
+        }
+        let (path, outdir) = if multi_app {
+            let path = format!("{}/{}/", APPS_DIR, name);
+            let outdir = build_args.outdir().join(APPS_DIR).join(&name);
+            (Some(path), outdir)
+        } else {
+            (None, build_args.outdir().to_path_buf())
+        };
+        let config = Config::load(appdir, build_args.config())?;
+        let entry = match build_args
+            .runtime()
+            .entry()
+            .or(config.runtime.entry.as_deref())
+        {
+            Some(entry) => Entry::from(entry),
+            None => Entry::detect(appdir),
+        };
+        let html = HtmlContext::new(build_args.mode(), build_args.html(), &config.html, appdir);
+        html.check()?;
+        targets.push(AppTarget {
+            name,
+            appdir: appdir.clone(),
+            path,
+            outdir,
+            config,
+            entry,
+            html,
+        });
+    }
+    Ok(targets)
+}
+
+/// Rebuilds the tarball of the app in `appdir` into the directory of its
+/// page, leaving the rest of the bundle as it is.
+pub fn build_app(build_args: &BuildArgs, appdir: &Path) -> BundlerResult<()> {
+    let apps = app_targets(build_args)?;
+    let Some(app) = apps.iter().find(|app| app.appdir == appdir) else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(&app.outdir).map_err(Error::bundle(&app.outdir))?;
+    let files = build_bundle(&app.appdir, &app.outdir, &build_args.bundle_options())?;
+    app.entry.validate(&app.appdir, &files)
+}
+
+/// The `[http]` tables of the apps merged, their CA certificates resolved
+/// against the app directories.
+fn http_config(apps: &[AppTarget]) -> BundlerResult<HttpConfig> {
+    let mut http = HttpConfig::default();
+    for app in apps {
+        let mut app_http = app.config.http.clone();
+        app_http.ca_cert = app_http.ca_cert.map(|path| app.appdir.join(path));
+        http.merge(app_http).map_err(|setting| Error::HttpConfig {
+            what: format!("[http] table of {}", app.name),
+            reason: format!("its {} differs from the one of another app", setting),
+        })?;
+    }
+    Ok(http)
+}
+
+/// Prints what a build would bundle: the files of every app, the files the
+/// ignore rules leave out, the resolved packages and their download size.
+/// Only the package index and the headers of the package tarballs are
+/// fetched. With `--output json` the listing is reported as events.
+async fn dry_run(build_args: &BuildArgs, apps: &[AppTarget]) -> BundlerResult<()> {
+    let mut renv_lock = RenvLock::default();
+    let mut file_count = 0;
+    for app in apps {
+        let listing = list_files(&app.appdir, &build_args.bundle_options())?;
+        file_count += listing.included.len();
+        if json_output() {
+            emit(Event::AppStarted {
+                app: app.name.clone(),
+                appdir: app.appdir.clone(),
+            });
+            for (path, size) in &listing.included {
+                let (kind, size) = match listing.symlinks.contains(path) {
+                    true => (FileKind::Symlink, None),
+                    false => (FileKind::File, Some(*size)),
+                };
+                emit(Event::FileAdded {
+                    path: path.clone(),
+                    kind,
+                    size,
+                });
+            }
+            for (path, reason) in &listing.excluded {
+                emit(Event::FileExcluded {
+                    path: path.clone(),
+                    reason: reason.clone(),
+                });
+            }
+        } else {
+            print_listing(app, &listing);
+        }
+        let files = listing
+            .included
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<BTreeSet<_>>();
+        if let Err(err) = app.entry.validate(&app.appdir, &files) {
+            warn!("{}", err);
+        }
+        renv_lock.merge(&app.name, RenvLock::read_from_file(&app.appdir)?);
+    }
+    let client = HttpClient::new(build_args.http(), &http_config(apps)?, ".")?;
+    let repository = PackageRepository::new(
+        open_repository(build_args.repo(), client, build_args.r_version())?,
+        build_args.r_version(),
+    );
+    renv_lock.resolve(&repository).await?;
+    let sizes = renv_lock
+        .download_sizes(&repository, build_args.parallel())
+        .await?;
+    let total = sizes.iter().filter_map(|(_, size)| *size).sum();
+    if json_output() {
+        for (package, size) in &sizes {
+            let (name, version) = package.get_package();
+            emit(Event::PackageResolved {
+                package: name.to_string(),
+                version: version.to_string(),
+                size: *size,
+            });
+        }
+        emit(Event::DryRunFinished {
+            files: file_count,
+            packages: sizes.len(),
+            download_size: total,
+        });
+        return Ok(());
+    }
+    println!("{} ({}):", "Packages".bold(), sizes.len());
+    for (package, size) in &sizes {
+        let size = match size {
+            Some(size) => format_size(*size),
+            None => "unknown".to_string(),
+        };
+        println!("  {:>10}  {}", size, package);
+    }
+    println!(
+        "Estimated package download size: {}",
+        format_size(total).cyan().bold()
+    );
+    Ok(())
+}
+
+/// Prints the included and excluded files of an app.
+fn print_listing(app: &AppTarget, listing: &FileListing) {
+    println!(
+        "{} {} ({})",
+        "App".bold(),
+        app.name.green().bold(),
+        app.appdir.display()
+    );
+    println!("Included files:");
+    for (path, size) in &listing.included {
+        println!("  {:>10}  {}", format_size(*size), path.display());
+    }
+    println!("Excluded files:");
+    for (path, reason) in &listing.excluded {
+        println!("  {}  {}", path, format!("({})", reason).dimmed());
+    }
+    let total = listing.included.iter().map(|(_, size)| size).sum();
+    println!(
+        "{} files, {} uncompressed\n",
+        listing.included.len(),
+        format_size(total).cyan()
+    );
+}
+
+/// Checks that the webR release `webr_version` can load the packages of the
+/// build and mount the filesystem images it ships.
+fn check_webr(build_args: &BuildArgs, webr_version: &str) -> BundlerResult<()> {
+    check_compatibility(webr_version, build_args.r_version())?;
+    if build_args.library_image() {
+        check_mount_support(webr_version, "--library-image")?;
+    }
+    if build_args.bundle_options().packaging == AppPackaging::Image {
+        check_mount_support(webr_version, "--packaging image")?;
+    }
+    Ok(())
+}
+
+/// Builds the whole bundle: the app tarballs, the package repository, the
+/// webR runtime when vendored and the generated pages.
+///
+/// With several app directories every app gets its own page under
+/// `apps/<name>/`, all of them sharing one package repository with the
+/// union of their dependencies, and the root `index.html` links to them.
+pub async fn build(build_args: &BuildArgs) -> BundlerResult<()> {
+    let outdir = build_args.outdir();
+    let mode = build_args.mode();
+    let r_version = build_args.r_version();
+    let mut webr_version = match build_args.local_webr() {
+        Some(path) => local_version(path, build_args.requested_webr_version())?,
+        None => build_args.webr_version(),
+    };
+    // A vendored `latest` is checked once resolved to the release it is
+    let resolve_latest = webr_version == "latest" && build_args.vendor_webr().is_some();
+    if !resolve_latest {
+        check_webr(build_args, &webr_version)?;
+    }
+    check_bundle_options(&build_args.bundle_options())?;
+    let apps = app_targets(build_args)?;
+    if build_args.dry_run() {
+        return dry_run(build_args, &apps).await;
+    }
+    let start_time = std::time::Instant::now();
+    emit(Event::BuildStarted {
+        outdir: outdir.to_path_buf(),
+        apps: apps.len(),
+    });
+    create_dist_dir(outdir)?;
+    let mut renv_lock = RenvLock::default();
+    let mut app_packages = Vec::with_capacity(apps.len());
+    for app in &apps {
+        emit(Event::AppStarted {
+            app: app.name.clone(),
+            appdir: app.appdir.clone(),
+        });
+        std::fs::create_dir_all(&app.outdir).map_err(Error::bundle(&app.outdir))?;
+        let files = build_bundle(&app.appdir, &app.outdir, &build_args.bundle_options())?;
+        app.entry.validate(&app.appdir, &files)?;
+        let app_lock = RenvLock::read_from_file(&app.appdir)?;
+        app_packages.push(app_lock.package_names());
+        renv_lock.merge(&app.name, app_lock);
+    }
+    let client = HttpClient::new(build_args.http(), &http_config(&apps)?, ".")?;
+    let webr = match build_args.vendor_webr() {
+        Some(spec) => {
+            if let Some(version) = vendor_webr(&client, outdir, spec).await? {
+                webr_version = version;
+            }
+            if resolve_latest {
+                check_webr(build_args, &webr_version)?;
+            }
+            WebRSource::Vendored
+        }
+        None => WebRSource::cdn(&webr_version),
+    };
+    let repository = PackageRepository::new(
+        open_repository(build_args.repo(), client.clone(), r_version)?,
+        r_version,
+    );
+    let failed_packages = renv_lock
+        .download(&repository, outdir, build_args.parallel())
+        .await?;
+    repository
+        .write_index(contrib_dir(outdir, r_version))
+        .await?;
+    if build_args.library_image() {
+        write_library_image(outdir, &renv_lock, r_version)?;
+    }
+    let mut manifest = BundleManifest::new(
+        outdir,
+        &webr_version,
+        &webr.base_url(),
+        r_version,
+        mode,
+        &renv_lock,
+    );
+    for (app, packages) in apps.iter().zip(app_packages) {
+        // Packages that are not available for webR were dropped while
+        // resolving the shared repository
+        let packages = renv_lock.install_order(&packages);
+        let depth = if app.path.is_some() { 2 } else { 0 };
+        manifest.add_app(
+            &app.name,
+            app.path.as_deref().unwrap_or("./"),
+            &app.entry,
+            &packages,
+        );
+        let mut js_context = JsContext::new(
+            packages,
+            webr.url_from(depth),
+            app.path.as_deref(),
+            app.entry.clone(),
+            &build_args.bundle_options(),
+            mode,
+            build_args.runtime(),
+            &app.config.runtime,
+        )?;
+        if build_args.library_image() {
+            js_context = js_context.with_library_image(&"../".repeat(depth));
+        }
+        write_javascript(&app.outdir, &js_context)?;
+        write_index_html_file(&app.outdir, &app.html)?;
+    }
+    if apps.len() > 1 {
+        let links = apps
+            .iter()
+            .map(|app| (app.name.clone(), app.path.clone().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        write_landing_page(outdir, build_args.html().title(), &links)?;
+    }
+    manifest.write(outdir)?;
+    let report = SizeReport::from_dir(outdir)?;
+    report.print();
+    if matches!(webr, WebRSource::Cdn { .. }) {
+        info!("The webR runtime is loaded from the CDN and not counted in the total");
+    }
+    if let Some(path) = build_args.size_report() {
+        report.write(path)?;
+    }
+    emit(Event::BuildFinished {
+        apps: apps.len(),
+        packages: renv_lock.packages().len(),
+        failed_packages: failed_packages
+            .iter()
+            .map(|package| package.get_package().0.to_string())
+            .collect(),
+        size: report.compressed(),
+        uncompressed_size: report.uncompressed(),
+        elapsed_ms: millis(start_time.elapsed()),
+    });
+    match build_args.max_size() {
+        Some(max_size) => report.check_budget(max_size),
+        None => Ok(()),
+    }
+}
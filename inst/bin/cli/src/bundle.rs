@@ -1,41 +1,315 @@
-use crate::errors::BundlerResult;
+use crate::cli::{AppPackaging, ArchiveFormat, SymlinkPolicy};
+use crate::config::CONFIG_FILE;
+use crate::errors::{BundlerResult, Error};
+use crate::events::{emit, Event, FileKind};
+use crate::image::FsImage;
+use crate::size::format_size;
 use colored::Colorize;
 use flate2::write::GzEncoder;
-use flate2::Compression;
-use ignore::{Walk, WalkBuilder};
-use std::fs::metadata;
+use flate2::{Compression, GzBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{DirEntry, Match, Walk, WalkBuilder};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::remove_dir_all;
 use std::fs::File;
+use std::fs::{metadata, Metadata};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-pub fn build_walker(appdir: impl AsRef<Path>) -> Walk {
+/// Files of R projects that are never needed to run the app. They are
+/// ignored unless a `.webrignore` or `.gitignore` rule negates them.
+const DEFAULT_IGNORES: &[&str] = &[
+    "renv/library/",
+    "renv/local/",
+    "renv/staging/",
+    "renv/sandbox/",
+    "packrat/",
+    "rsconnect/",
+    ".Rproj.user/",
+    "*.Rproj",
+    ".Rhistory",
+    ".RData",
+];
+
+/// Data file and metadata of the filesystem image of an app.
+pub const APP_IMAGE_DATA: &str = "app.data";
+pub const APP_IMAGE_METADATA: &str = "app.metadata.json";
+/// Directory holding the files of an app shipped one by one, and the
+/// manifest listing them.
+pub const APP_FILES_DIR: &str = "app";
+pub const APP_FILES_MANIFEST: &str = "app.files.json";
+
+/// Size above which bundling a file prints a warning, unless configured.
+const DEFAULT_WARN_FILE_SIZE: u64 = 10 << 20;
+
+/// Options of the app tarball.
+#[derive(Debug, Clone)]
+pub struct BundleOptions {
+    /// Write the same archive for the same files regardless of the
+    /// machine, the filesystem and when the files were checked out.
+    pub reproducible: bool,
+    pub packaging: AppPackaging,
+    /// Format of the archive when packaging the app as an archive.
+    pub format: ArchiveFormat,
+    /// Compression level, the format's default when `None`.
+    pub compression_level: Option<u32>,
+    pub symlinks: SymlinkPolicy,
+    /// Files larger than this are bundled with a warning.
+    pub warn_file_size: u64,
+    /// Files larger than this fail the build.
+    pub max_file_size: Option<u64>,
+    /// Apply `DEFAULT_IGNORES` before the rules of the app.
+    pub default_ignores: bool,
+    /// Where the bundle is written, left out of the app archive when it is
+    /// inside the app directory.
+    pub outdir: Option<PathBuf>,
+}
+
+impl Default for BundleOptions {
+    fn default() -> Self {
+        Self {
+            reproducible: false,
+            packaging: AppPackaging::default(),
+            format: ArchiveFormat::default(),
+            compression_level: None,
+            symlinks: SymlinkPolicy::default(),
+            warn_file_size: DEFAULT_WARN_FILE_SIZE,
+            max_file_size: None,
+            default_ignores: true,
+            outdir: None,
+        }
+    }
+}
+
+/// A rule of a `.webrignore` or `.gitignore` file.
+struct UserRule {
+    file: PathBuf,
+    pattern: String,
+    whitelist: bool,
+}
+
+/// The ignore rules of an app: the default ones and those of the
+/// `.webrignore` and `.gitignore` files of the app directory, each file
+/// being parsed once.
+struct IgnoreRules {
+    appdir: PathBuf,
+    defaults: Gitignore,
+    /// Parsed ignore files by path, `None` for the ones that don't exist.
+    files: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl IgnoreRules {
+    /// The default rules for the app in `appdir`, including the output
+    /// directory when it is inside the app directory.
+    fn new(appdir: &Path, options: &BundleOptions) -> BundlerResult<Self> {
+        let mut builder = GitignoreBuilder::new(appdir);
+        if options.default_ignores {
+            for rule in DEFAULT_IGNORES.iter().chain([&CONFIG_FILE]) {
+                builder
+                    .add_line(None, rule)
+                    .map_err(Error::bundle(appdir))?;
+            }
+        }
+        let outdir = options
+            .outdir
+            .as_ref()
+            .and_then(|outdir| outdir.canonicalize().ok());
+        let outdir = appdir
+            .canonicalize()
+            .ok()
+            .zip(outdir)
+            .and_then(|(appdir, outdir)| outdir.strip_prefix(appdir).ok().map(Path::to_path_buf));
+        if let Some(outdir) = outdir.filter(|outdir| !outdir.as_os_str().is_empty()) {
+            // Escape glob characters, the directory name is matched literally
+            let outdir = outdir
+                .to_string_lossy()
+                .chars()
+                .map(|c| match c {
+                    '*' | '?' | '[' | ']' | '{' | '}' | '!' | '\\' => format!("\\{}", c),
+                    c => c.to_string(),
+                })
+                .collect::<String>();
+            builder
+                .add_line(None, &format!("/{}/", outdir))
+                .map_err(Error::bundle(appdir))?;
+        }
+        Ok(Self {
+            appdir: appdir.to_path_buf(),
+            defaults: builder.build().map_err(Error::bundle(appdir))?,
+            files: Mutex::default(),
+        })
+    }
+    /// The rules of the ignore file `file`, `None` when it doesn't exist.
+    /// Files with invalid rules fail, as the walker would skip those rules.
+    fn ignore_file(&self, file: PathBuf) -> BundlerResult<Option<Arc<Gitignore>>> {
+        let mut files = self
+            .files
+            .lock()
+            .expect("Ignore files lock is not poisoned");
+        if let Some(matcher) = files.get(&file) {
+            return Ok(matcher.clone());
+        }
+        let matcher = match file.is_file() {
+            true => match Gitignore::new(&file) {
+                (_, Some(err)) => return Err(Error::bundle(&file)(err)),
+                (matcher, None) => Some(Arc::new(matcher)),
+            },
+            false => None,
+        };
+        files.insert(file, matcher.clone());
+        Ok(matcher)
+    }
+    /// Finds the rule of the app deciding whether `path` is ignored.
+    /// `.webrignore` files take precedence over `.gitignore` files, and for
+    /// each of them the one in the deepest directory wins, as in the
+    /// walker. Only the files inside the app directory are looked at.
+    fn user_rule(&self, path: &Path, is_dir: bool) -> BundlerResult<Option<UserRule>> {
+        for filename in [".webrignore", ".gitignore"] {
+            let dirs = path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(&self.appdir));
+            for dir in dirs {
+                let file = dir.join(filename);
+                let Some(matcher) = self.ignore_file(file.clone())? else {
+                    continue;
+                };
+                let (whitelist, glob) = match matcher.matched(path, is_dir) {
+                    Match::Ignore(glob) => (false, glob.original().to_string()),
+                    Match::Whitelist(glob) => (true, glob.original().to_string()),
+                    Match::None => continue,
+                };
+                return Ok(Some(UserRule {
+                    file,
+                    pattern: glob,
+                    whitelist,
+                }));
+            }
+        }
+        Ok(None)
+    }
+    fn is_whitelisted(&self, path: &Path, is_dir: bool) -> BundlerResult<bool> {
+        Ok(self
+            .user_rule(path, is_dir)?
+            .is_some_and(|rule| rule.whitelist))
+    }
+    /// Whether a default rule ignores `path` without the app negating it.
+    fn is_default_ignored(&self, path: &Path, is_dir: bool) -> BundlerResult<bool> {
+        Ok(
+            self.defaults.matched(path, is_dir).is_ignore()
+                && !self.is_whitelisted(path, is_dir)?,
+        )
+    }
+    /// Whether `path` is a hidden file left out of the bundle. Negating a
+    /// hidden file brings it back, as for `.Rhistory` or `.RData` which
+    /// are ignored by default.
+    fn is_hidden(&self, path: &Path, is_dir: bool) -> BundlerResult<bool> {
+        Ok(path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            && !self.is_whitelisted(path, is_dir)?)
+    }
+    /// Finds the rule excluding `path` from the bundle.
+    fn exclusion_reason(&self, path: &Path, is_dir: bool) -> BundlerResult<String> {
+        if let Match::Ignore(glob) = self.defaults.matched(path, is_dir) {
+            if self.is_default_ignored(path, is_dir)? {
+                return Ok(format!("default rule: {}", glob.original()));
+            }
+        }
+        if self.is_hidden(path, is_dir)? {
+            return Ok("hidden".to_string());
+        }
+        Ok(match self.user_rule(path, is_dir)? {
+            Some(rule) if !rule.whitelist => {
+                let file = rule.file.strip_prefix(&self.appdir).unwrap_or(&rule.file);
+                format!("{}: {}", file.display(), rule.pattern)
+            }
+            _ => "git exclude rules".to_string(),
+        })
+    }
+}
+
+/// An error of the walker as a bundle error, naming the file it is about
+/// when it tells, e.g. an ignore file with an invalid rule.
+fn walk_error(appdir: &Path, err: &ignore::Error) -> Error {
+    fn path_of(err: &ignore::Error) -> Option<&Path> {
+        match err {
+            ignore::Error::WithPath { path, .. } => Some(path),
+            ignore::Error::Loop { child, .. } => Some(child),
+            ignore::Error::WithLineNumber { err, .. } | ignore::Error::WithDepth { err, .. } => {
+                path_of(err)
+            }
+            ignore::Error::Partial(errs) => errs.iter().find_map(path_of),
+            _ => None,
+        }
+    }
+    Error::bundle(path_of(err).unwrap_or(appdir))(err)
+}
+
+/// The entry yielded by the walker, failing on its error or on the one of
+/// the ignore files of the directory it is.
+fn walk_entry(appdir: &Path, result: Result<DirEntry, ignore::Error>) -> BundlerResult<DirEntry> {
+    let entry = result.map_err(|err| walk_error(appdir, &err))?;
+    match entry.error() {
+        Some(err) => Err(walk_error(appdir, err)),
+        None => Ok(entry),
+    }
+}
+
+pub fn build_walker(appdir: impl AsRef<Path>, options: &BundleOptions) -> BundlerResult<Walk> {
+    let rules = IgnoreRules::new(appdir.as_ref(), options)?;
+    let skip_symlinks = options.symlinks == SymlinkPolicy::Skip;
     let mut walk_builder = WalkBuilder::new(appdir);
     walk_builder.add_custom_ignore_filename(".webrignore");
     walk_builder.git_ignore(true);
     walk_builder.require_git(false);
-    walk_builder.hidden(true);
-    walk_builder.build()
+    // Only the rules of the app directory apply, not those of its parents
+    walk_builder.parents(false);
+    // Hidden files are filtered below, so that rules can negate them
+    walk_builder.hidden(false);
+    walk_builder.follow_links(options.symlinks == SymlinkPolicy::Follow);
+    walk_builder.filter_entry(move |entry| {
+        if entry.depth() == 0 {
+            return true;
+        }
+        if skip_symlinks && entry.path_is_symlink() {
+            return false;
+        }
+        let is_dir = entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_dir());
+        // Invalid ignore files are reported by the walker, which reads them too
+        let excluded = |rule: BundlerResult<bool>| rule.unwrap_or(false);
+        !excluded(rules.is_default_ignored(entry.path(), is_dir))
+            && !excluded(rules.is_hidden(entry.path(), is_dir))
+    });
+    // A stable order keeps archives and file listings comparable
+    walk_builder.sort_by_file_name(|a, b| a.cmp(b));
+    Ok(walk_builder.build())
 }
 
 pub fn print_note() {
     let webrignore = ".webrignore".green().bold();
     let gitignore = ".gitignore".green().bold();
-    eprintln!("{:-^40}", "NOTE".yellow().bold());
-    eprintln!("{webrignore} and {gitignore} are used to ignore files and directories.");
-    eprintln!("Even if you don't use git, rules in {gitignore} files will be enforced.",);
-    eprintln!("{:-^40}", "----".yellow().bold());
+    info!("{:-^40}", "NOTE".yellow().bold());
+    info!("{webrignore} and {gitignore} are used to ignore files and directories.");
+    info!("Even if you don't use git, rules in {gitignore} files will be enforced.",);
+    info!("Common R project files like renv/library and rsconnect/ are ignored by default.");
+    info!("{:-^40}", "----".yellow().bold());
 }
 
-fn add_dist_ignore(outdir: impl AsRef<Path>) -> BundlerResult<()> {
+fn add_dist_ignore(outdir: impl AsRef<Path>) -> std::io::Result<()> {
     let mut dist_ignore = File::create(outdir.as_ref().join(".webrignore"))?;
     dist_ignore.write_all(r#"**/**"#.as_bytes())?;
     Ok(())
 }
 
-fn delete_dist_dir(outdir: impl AsRef<Path>) -> BundlerResult<()> {
+fn delete_dist_dir(outdir: impl AsRef<Path>) -> std::io::Result<()> {
     if outdir.as_ref().exists() {
-        eprintln!(
+        info!(
             "Deleting contents from {}...",
             outdir.as_ref().display().to_string().green().bold()
         );
@@ -46,37 +320,707 @@ fn delete_dist_dir(outdir: impl AsRef<Path>) -> BundlerResult<()> {
 
 pub fn create_dist_dir(outdir: impl AsRef<Path>) -> BundlerResult<()> {
     // Check if the dist directory exists and delete it if it does.
-    delete_dist_dir(outdir.as_ref())?;
-    std::fs::create_dir_all(outdir.as_ref())?;
-    add_dist_ignore(outdir.as_ref())
+    delete_dist_dir(outdir.as_ref())
+        .and_then(|_| std::fs::create_dir_all(outdir.as_ref()))
+        .and_then(|_| add_dist_ignore(outdir.as_ref()))
+        .map_err(Error::bundle(outdir))
 }
 
-pub fn build_bundle(appdir: impl AsRef<Path>, outdir: impl AsRef<Path>) -> BundlerResult<()> {
-    eprintln!("Building bundle...");
-    print_note();
-    let tar_gz = File::create(outdir.as_ref().join("app.tgz"))?;
-    let enc = GzEncoder::new(tar_gz, Compression::default());
-    let mut tar = tar::Builder::new(enc);
-    for result in build_walker(appdir.as_ref()) {
-        // Each item yielded by the iterator is either a directory entry or an
-        // error, so either print the path or the error.
-        match result {
-            Ok(entry) => {
-                let metadata = metadata(entry.path())?;
-                if metadata.is_file() {
-                    eprintln!(
-                        "Adding {} to bundle...",
-                        entry.path().display().to_string().green().bold()
-                    );
-                    tar.append_path_with_name(
-                        entry.path(),
-                        entry.path().strip_prefix(appdir.as_ref())?,
-                    )?;
-                }
+/// Modification time of every file in a reproducible archive, taken from
+/// `SOURCE_DATE_EPOCH` like other reproducible build tools do.
+fn source_date_epoch() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Appends a file with a normalized header: fixed mtime, root owner and
+/// either 0644 or 0755 permissions.
+fn append_normalized<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &Metadata,
+    mtime: u64,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(metadata.len());
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mode(if is_executable(metadata) {
+        0o755
+    } else {
+        0o644
+    });
+    tar.append_data(&mut header, name, File::open(path)?)
+}
+
+/// Files of an app directory split into the ones going into the bundle and
+/// the ones left out. Paths are relative to the app directory.
+#[derive(Debug, Default)]
+pub struct FileListing {
+    /// Bundled files and their sizes.
+    pub included: Vec<(PathBuf, u64)>,
+    /// The included paths that are preserved symlinks, listed with a size
+    /// of 0.
+    pub symlinks: BTreeSet<PathBuf>,
+    /// Excluded files and directories, with the reason they were excluded.
+    /// Directories end with a `/` and their contents are not listed.
+    pub excluded: Vec<(String, String)>,
+}
+
+fn list_dir(
+    appdir: &Path,
+    dir: &Path,
+    included: &BTreeSet<PathBuf>,
+    options: &BundleOptions,
+    rules: &IgnoreRules,
+    listing: &mut FileListing,
+) -> BundlerResult<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(Error::bundle(dir))?;
+    entries.sort();
+    for path in entries {
+        let is_link = std::fs::symlink_metadata(&path)
+            .map_err(Error::bundle(&path))?
+            .is_symlink();
+        let is_dir = match options.symlinks {
+            SymlinkPolicy::Follow => path.is_dir(),
+            _ => !is_link && path.is_dir(),
+        };
+        let name = path.strip_prefix(appdir)?;
+        if !included.contains(&path) {
+            let name = if is_dir {
+                format!("{}/", name.display())
+            } else {
+                name.display().to_string()
+            };
+            let reason = if is_link && options.symlinks == SymlinkPolicy::Skip {
+                "symlink".to_string()
+            } else {
+                rules.exclusion_reason(&path, is_dir)?
+            };
+            listing.excluded.push((name, reason));
+        } else if is_dir {
+            list_dir(appdir, &path, included, options, rules, listing)?;
+        } else if is_link && options.symlinks == SymlinkPolicy::Preserve {
+            listing.included.push((name.to_path_buf(), 0));
+            listing.symlinks.insert(name.to_path_buf());
+        } else {
+            let metadata = metadata(&path).map_err(Error::bundle(&path))?;
+            if metadata.is_file() {
+                listing.included.push((name.to_path_buf(), metadata.len()));
             }
-            Err(err) => eprintln!("{}: {}", "ERROR".red().bold(), err),
         }
     }
-    tar.into_inner()?.finish()?;
     Ok(())
 }
+
+/// Lists what `build_bundle` would put in the archive without writing it,
+/// along with everything the ignore rules leave out.
+pub fn list_files(appdir: impl AsRef<Path>, options: &BundleOptions) -> BundlerResult<FileListing> {
+    let appdir = appdir.as_ref();
+    let included = build_walker(appdir, options)?
+        .map(|result| walk_entry(appdir, result).map(DirEntry::into_path))
+        .collect::<BundlerResult<BTreeSet<_>>>()?;
+    let mut listing = FileListing::default();
+    let rules = IgnoreRules::new(appdir, options)?;
+    list_dir(appdir, appdir, &included, options, &rules, &mut listing)?;
+    Ok(listing)
+}
+
+/// Appends a directory, so that empty directories survive extraction.
+fn append_dir<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    options: &BundleOptions,
+    mtime: u64,
+) -> std::io::Result<()> {
+    if !options.reproducible {
+        return tar.append_dir(name, path);
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mode(0o755);
+    tar.append_data(&mut header, name, std::io::empty())
+}
+
+/// Whether the link `name`, relative to the app directory, points outside
+/// of it.
+fn links_outside(name: &Path, target: &Path) -> bool {
+    if target.is_absolute() {
+        return true;
+    }
+    let mut depth = 0usize;
+    for component in name
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(target)
+        .components()
+    {
+        match component {
+            std::path::Component::ParentDir if depth == 0 => return true,
+            std::path::Component::ParentDir => depth -= 1,
+            std::path::Component::CurDir => {}
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+/// Appends a symbolic link as a link. Links pointing outside of the app
+/// directory will be dangling once extracted, so they are reported.
+fn append_symlink<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    options: &BundleOptions,
+    mtime: u64,
+) -> std::io::Result<()> {
+    let target = std::fs::read_link(path)?;
+    if links_outside(name, &target) {
+        warn!(
+            "{} links to {}, outside of the app directory",
+            name.display().to_string().green().bold(),
+            target.display()
+        );
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(0o777);
+    if options.reproducible {
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+    } else {
+        header.set_metadata(&std::fs::symlink_metadata(path)?);
+    }
+    tar.append_link(&mut header, name, target)
+}
+
+/// Warns about or rejects files above the configured sizes.
+fn check_file_size(name: &Path, size: u64, options: &BundleOptions) -> BundlerResult<()> {
+    if let Some(max_size) = options.max_file_size.filter(|max_size| size > *max_size) {
+        return Err(Error::FileTooLarge {
+            path: name.to_path_buf(),
+            size,
+            max_size,
+        });
+    }
+    if size > options.warn_file_size {
+        warn!(
+            "{} is {}, consider excluding it with .webrignore",
+            name.display().to_string().green().bold(),
+            format_size(size)
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the packaging options are valid.
+pub fn check_bundle_options(options: &BundleOptions) -> BundlerResult<()> {
+    if options.packaging != AppPackaging::Archive {
+        if options.symlinks == SymlinkPolicy::Preserve {
+            return Err(Error::PreservedSymlinks);
+        }
+        return Ok(());
+    }
+    if let Some(level) = options.compression_level {
+        match options.format.levels() {
+            Some((min, max)) if (min..=max).contains(&level) => {}
+            _ => return Err(Error::CompressionLevel(options.format, level)),
+        }
+    }
+    Ok(())
+}
+
+/// Compressed stream the app archive is written to.
+enum ArchiveWriter {
+    Tar(File),
+    Gzip(GzEncoder<File>),
+    Xz(xz2::write::XzEncoder<File>),
+}
+
+impl ArchiveWriter {
+    fn new(file: File, options: &BundleOptions) -> BundlerResult<Self> {
+        let level = options.compression_level;
+        Ok(match options.format {
+            ArchiveFormat::Tar => ArchiveWriter::Tar(file),
+            ArchiveFormat::Tgz => {
+                let compression = level.map(Compression::new).unwrap_or_default();
+                ArchiveWriter::Gzip(if options.reproducible {
+                    // No timestamp and an "unknown" OS in the gzip header
+                    GzBuilder::new()
+                        .mtime(0)
+                        .operating_system(255)
+                        .write(file, compression)
+                } else {
+                    GzEncoder::new(file, compression)
+                })
+            }
+            ArchiveFormat::Xz => {
+                ArchiveWriter::Xz(xz2::write::XzEncoder::new(file, level.unwrap_or(6)))
+            }
+        })
+    }
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Tar(mut file) => file.flush(),
+            ArchiveWriter::Gzip(encoder) => encoder.finish()?.flush(),
+            ArchiveWriter::Xz(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveWriter::Tar(file) => file.write(buf),
+            ArchiveWriter::Gzip(encoder) => encoder.write(buf),
+            ArchiveWriter::Xz(encoder) => encoder.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveWriter::Tar(file) => file.flush(),
+            ArchiveWriter::Gzip(encoder) => encoder.flush(),
+            ArchiveWriter::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A file, directory or preserved link going into the bundle.
+enum EntryKind {
+    Dir,
+    File(Metadata),
+    Symlink,
+}
+
+struct BundleEntry {
+    path: PathBuf,
+    /// Path relative to the app directory.
+    name: PathBuf,
+    kind: EntryKind,
+}
+
+/// Walks the app directory and returns what goes into the bundle, parents
+/// before their contents.
+fn bundle_entries(appdir: &Path, options: &BundleOptions) -> BundlerResult<Vec<BundleEntry>> {
+    let mut entries = Vec::new();
+    for result in build_walker(appdir, options)? {
+        let entry = walk_entry(appdir, result)?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let name = entry.path().strip_prefix(appdir)?.to_path_buf();
+        let kind = if entry.path_is_symlink() && options.symlinks == SymlinkPolicy::Preserve {
+            EntryKind::Symlink
+        } else {
+            let metadata = metadata(entry.path()).map_err(Error::bundle(entry.path()))?;
+            if metadata.is_dir() {
+                EntryKind::Dir
+            } else if metadata.is_file() {
+                check_file_size(&name, metadata.len(), options)?;
+                EntryKind::File(metadata)
+            } else {
+                continue;
+            }
+        };
+        if !matches!(kind, EntryKind::Dir) {
+            info!(
+                "Adding {} to bundle...",
+                entry.path().display().to_string().green().bold()
+            );
+        }
+        let (file_kind, size) = match &kind {
+            EntryKind::File(metadata) => (FileKind::File, Some(metadata.len())),
+            EntryKind::Dir => (FileKind::Dir, None),
+            EntryKind::Symlink => (FileKind::Symlink, None),
+        };
+        emit(Event::FileAdded {
+            path: name.clone(),
+            kind: file_kind,
+            size,
+        });
+        entries.push(BundleEntry {
+            path: entry.into_path(),
+            name,
+            kind,
+        });
+    }
+    Ok(entries)
+}
+
+fn write_archive(
+    outdir: &Path,
+    entries: &[BundleEntry],
+    options: &BundleOptions,
+) -> BundlerResult<()> {
+    let archive_path = outdir.join(options.format.file_name());
+    let archive = File::create(&archive_path).map_err(Error::bundle(&archive_path))?;
+    let mtime = source_date_epoch();
+    let mut tar = tar::Builder::new(ArchiveWriter::new(archive, options)?);
+    for entry in entries {
+        let (path, name) = (entry.path.as_path(), entry.name.as_path());
+        match &entry.kind {
+            EntryKind::Dir => append_dir(&mut tar, path, name, options, mtime),
+            EntryKind::Symlink => append_symlink(&mut tar, path, name, options, mtime),
+            EntryKind::File(metadata) if options.reproducible => {
+                append_normalized(&mut tar, path, name, metadata, mtime)
+            }
+            EntryKind::File(_) => tar.append_path_with_name(path, name),
+        }
+        .map_err(Error::bundle(path))?;
+    }
+    tar.into_inner()
+        .and_then(ArchiveWriter::finish)
+        .map_err(Error::bundle(&archive_path))
+}
+
+/// Whether a file of `entries` is inside the directory `dir`.
+fn has_files(entries: &[BundleEntry], dir: &Path) -> bool {
+    entries
+        .iter()
+        .any(|entry| matches!(entry.kind, EntryKind::File(_)) && entry.name.starts_with(dir))
+}
+
+/// Writes the filesystem image of the app. Images only hold files, their
+/// directories being created from the file paths when mounted, so empty
+/// directories are left out with a warning.
+fn write_image(outdir: &Path, entries: &[BundleEntry]) -> BundlerResult<()> {
+    let mut image = FsImage::create(outdir.join(APP_IMAGE_DATA))?;
+    for entry in entries {
+        match entry.kind {
+            EntryKind::File(_) => File::open(&entry.path)
+                .and_then(|file| image.add(&entry.name, file))
+                .map_err(Error::bundle(&entry.path))?,
+            EntryKind::Dir if !has_files(entries, &entry.name) => warn!(
+                "{} is empty and left out of the image, use another --packaging to keep it",
+                entry.name.display().to_string().green().bold()
+            ),
+            _ => {}
+        }
+    }
+    image.finish(outdir.join(APP_IMAGE_METADATA))
+}
+
+#[derive(Serialize, Debug, Default)]
+struct FilesManifest {
+    directories: Vec<String>,
+    files: Vec<String>,
+}
+
+/// Path with `/` separators, as used in the browser.
+fn url_path(name: &Path) -> String {
+    name.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn write_files(outdir: &Path, entries: &[BundleEntry]) -> BundlerResult<()> {
+    let files_dir = outdir.join(APP_FILES_DIR);
+    if files_dir.exists() {
+        remove_dir_all(&files_dir).map_err(Error::bundle(&files_dir))?;
+    }
+    std::fs::create_dir_all(&files_dir).map_err(Error::bundle(&files_dir))?;
+    let mut manifest = FilesManifest::default();
+    for entry in entries {
+        match entry.kind {
+            EntryKind::Dir => {
+                let dir = files_dir.join(&entry.name);
+                std::fs::create_dir_all(&dir).map_err(Error::bundle(&dir))?;
+                manifest.directories.push(url_path(&entry.name));
+            }
+            _ => {
+                std::fs::copy(&entry.path, files_dir.join(&entry.name))
+                    .map_err(Error::bundle(&entry.path))?;
+                manifest.files.push(url_path(&entry.name));
+            }
+        }
+    }
+    let manifest_path = outdir.join(APP_FILES_MANIFEST);
+    std::fs::write(&manifest_path, serde_json::to_string(&manifest)?)
+        .map_err(Error::bundle(&manifest_path))
+}
+
+/// Writes the app files in the configured packaging and returns their
+/// paths relative to the app directory.
+pub fn build_bundle(
+    appdir: impl AsRef<Path>,
+    outdir: impl AsRef<Path>,
+    options: &BundleOptions,
+) -> BundlerResult<BTreeSet<PathBuf>> {
+    info!("Building bundle...");
+    print_note();
+    let entries = bundle_entries(appdir.as_ref(), options)?;
+    match options.packaging {
+        AppPackaging::Archive => write_archive(outdir.as_ref(), &entries, options)?,
+        AppPackaging::Image => write_image(outdir.as_ref(), &entries)?,
+        AppPackaging::Files => write_files(outdir.as_ref(), &entries)?,
+    }
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !matches!(entry.kind, EntryKind::Dir))
+        .map(|entry| entry.name)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_reproducible_bundle() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(appdir.path().join("www")).unwrap();
+        std::fs::write(appdir.path().join("app.R"), "shinyApp(ui, server)").unwrap();
+        std::fs::write(appdir.path().join("www").join("style.css"), "body {}").unwrap();
+        let options = BundleOptions {
+            reproducible: true,
+            ..Default::default()
+        };
+
+        let first = tempfile::tempdir().unwrap();
+        build_bundle(appdir.path(), first.path(), &options).unwrap();
+        File::options()
+            .write(true)
+            .open(appdir.path().join("app.R"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        let second = tempfile::tempdir().unwrap();
+        build_bundle(appdir.path(), second.path(), &options).unwrap();
+
+        let first = std::fs::read(first.path().join("app.tgz")).unwrap();
+        let second = std::fs::read(second.path().join("app.tgz")).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_archive_formats() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::write(appdir.path().join("app.R"), "shinyApp(ui, server)").unwrap();
+        for format in [ArchiveFormat::Tgz, ArchiveFormat::Tar, ArchiveFormat::Xz] {
+            let options = BundleOptions {
+                format,
+                compression_level: format.levels().map(|(_, max)| max),
+                ..Default::default()
+            };
+            let outdir = tempfile::tempdir().unwrap();
+            build_bundle(appdir.path(), outdir.path(), &options).unwrap();
+            let archive = File::open(outdir.path().join(format.file_name())).unwrap();
+            let reader: Box<dyn std::io::Read> = match format {
+                ArchiveFormat::Tgz => Box::new(flate2::read::GzDecoder::new(archive)),
+                ArchiveFormat::Tar => Box::new(archive),
+                ArchiveFormat::Xz => Box::new(xz2::read::XzDecoder::new(archive)),
+            };
+            let names = tar::Archive::new(reader)
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().into_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec![PathBuf::from("app.R")], "{} archive", format);
+        }
+    }
+
+    #[test]
+    fn test_image_and_files_packaging() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(appdir.path().join("www").join("uploads")).unwrap();
+        std::fs::write(appdir.path().join("app.R"), "shinyApp(ui, server)").unwrap();
+        std::fs::write(appdir.path().join("www").join("style.css"), "body {}").unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let options = |packaging| BundleOptions {
+            packaging,
+            ..Default::default()
+        };
+
+        build_bundle(appdir.path(), outdir.path(), &options(AppPackaging::Image)).unwrap();
+        let data = std::fs::read(outdir.path().join(APP_IMAGE_DATA)).unwrap();
+        let metadata = std::fs::read_to_string(outdir.path().join(APP_IMAGE_METADATA)).unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(&metadata).unwrap();
+        let style = &metadata["files"][1];
+        assert_eq!(style["filename"], "/www/style.css");
+        let (start, end) = (
+            style["start"].as_u64().unwrap(),
+            style["end"].as_u64().unwrap(),
+        );
+        assert_eq!(&data[start as usize..end as usize], b"body {}");
+
+        build_bundle(appdir.path(), outdir.path(), &options(AppPackaging::Files)).unwrap();
+        let manifest = std::fs::read_to_string(outdir.path().join(APP_FILES_MANIFEST)).unwrap();
+        assert_eq!(
+            manifest,
+            r#"{"directories":["www","www/uploads"],"files":["app.R","www/style.css"]}"#
+        );
+        let style = outdir
+            .path()
+            .join(APP_FILES_DIR)
+            .join("www")
+            .join("style.css");
+        assert_eq!(std::fs::read_to_string(style).unwrap(), "body {}");
+    }
+
+    #[test]
+    fn test_check_bundle_options() {
+        let options = |format, compression_level| BundleOptions {
+            format,
+            compression_level,
+            ..Default::default()
+        };
+        assert!(check_bundle_options(&options(ArchiveFormat::Tgz, Some(9))).is_ok());
+        assert!(check_bundle_options(&options(ArchiveFormat::Tgz, Some(10))).is_err());
+        assert!(check_bundle_options(&options(ArchiveFormat::Tar, Some(1))).is_err());
+        assert!(check_bundle_options(&options(ArchiveFormat::Xz, Some(9))).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_empty_dirs_and_symlinks() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(appdir.path().join("www").join("uploads")).unwrap();
+        std::fs::write(appdir.path().join("app.R"), "shinyApp(ui, server)").unwrap();
+        std::os::unix::fs::symlink("app.R", appdir.path().join("main.R")).unwrap();
+        let options = BundleOptions {
+            symlinks: SymlinkPolicy::Preserve,
+            ..Default::default()
+        };
+        let outdir = tempfile::tempdir().unwrap();
+        build_bundle(appdir.path(), outdir.path(), &options).unwrap();
+
+        let archive = File::open(outdir.path().join("app.tgz")).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let link = entry.link_name().unwrap().map(|link| link.into_owned());
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().entry_type(),
+                    link,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(entries.contains(&(
+            PathBuf::from("www/uploads"),
+            tar::EntryType::Directory,
+            None
+        )));
+        assert!(entries.contains(&(
+            PathBuf::from("main.R"),
+            tar::EntryType::Symlink,
+            Some(PathBuf::from("app.R"))
+        )));
+        assert!(links_outside(
+            Path::new("www/data"),
+            Path::new("../../data")
+        ));
+        assert!(!links_outside(Path::new("www/data"), Path::new("../data")));
+    }
+
+    #[test]
+    fn test_parent_ignore_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(".gitignore"), "*.csv\n").unwrap();
+        let appdir = root.path().join("app");
+        std::fs::create_dir(&appdir).unwrap();
+        std::fs::write(appdir.join("app.R"), "").unwrap();
+        std::fs::write(appdir.join("data.csv"), "a,b").unwrap();
+        let listing = list_files(&appdir, &BundleOptions::default()).unwrap();
+        assert_eq!(
+            listing.included,
+            vec![(PathBuf::from("app.R"), 0), (PathBuf::from("data.csv"), 3)]
+        );
+    }
+
+    #[test]
+    fn test_invalid_ignore_file() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(appdir.path().join("data")).unwrap();
+        std::fs::write(appdir.path().join("app.R"), "").unwrap();
+        let ignore_file = appdir.path().join("data").join(".webrignore");
+        std::fs::write(&ignore_file, "*.csv\nraw[\n").unwrap();
+        std::fs::write(appdir.path().join("data").join("big.csv"), "a,b").unwrap();
+        assert!(matches!(
+            list_files(appdir.path(), &BundleOptions::default()),
+            Err(Error::Bundle { path, .. }) if path == ignore_file
+        ));
+        let outdir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            build_bundle(appdir.path(), outdir.path(), &BundleOptions::default()),
+            Err(Error::Bundle { path, .. }) if path == ignore_file
+        ));
+    }
+
+    #[test]
+    fn test_list_files() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(appdir.path().join("data")).unwrap();
+        std::fs::write(appdir.path().join("app.R"), "shinyApp(ui, server)").unwrap();
+        std::fs::write(appdir.path().join(".Rhistory"), "").unwrap();
+        std::fs::write(appdir.path().join(".gitignore"), "data/\n*.log\n").unwrap();
+        std::fs::write(appdir.path().join(".webrignore"), "!packrat/\n!.RData\n").unwrap();
+        std::fs::write(appdir.path().join(".RData"), "RDX3").unwrap();
+        std::fs::create_dir(appdir.path().join("rsconnect")).unwrap();
+        std::fs::create_dir(appdir.path().join("packrat")).unwrap();
+        std::fs::write(appdir.path().join("packrat").join("packrat.lock"), "").unwrap();
+        std::fs::write(appdir.path().join("debug.log"), "").unwrap();
+        std::fs::write(appdir.path().join("data").join("big.csv"), "a,b").unwrap();
+
+        let listing = list_files(appdir.path(), &BundleOptions::default()).unwrap();
+        assert_eq!(
+            listing.included,
+            vec![
+                (PathBuf::from(".RData"), 4),
+                (PathBuf::from("app.R"), 20),
+                (PathBuf::from("packrat/packrat.lock"), 0)
+            ]
+        );
+        assert_eq!(
+            listing.excluded,
+            vec![
+                (
+                    ".Rhistory".to_string(),
+                    "default rule: .Rhistory".to_string()
+                ),
+                (".gitignore".to_string(), "hidden".to_string()),
+                (".webrignore".to_string(), "hidden".to_string()),
+                ("data/".to_string(), ".gitignore: data/".to_string()),
+                ("debug.log".to_string(), ".gitignore: *.log".to_string()),
+                (
+                    "rsconnect/".to_string(),
+                    "default rule: rsconnect/".to_string()
+                ),
+            ]
+        );
+    }
+}
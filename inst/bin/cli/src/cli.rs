@@ -1,4 +1,9 @@
-use clap::Parser;
+use crate::bundle::BundleOptions;
+use crate::repository::DEFAULT_REPO;
+use crate::size::parse_size;
+use crate::webr::{normalize_version, DEFAULT_WEBR_VERSION};
+use clap::{Parser, ValueEnum};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 
 /// Bundle Shiny Applications for WebR in seconds!
@@ -7,21 +12,150 @@ use std::path::{Path, PathBuf};
 pub struct Args {
     #[command(subcommand)]
     command: Command,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// When output is colored.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// Color when printing to a terminal and NO_COLOR is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Verbosity and destination of the messages, valid for every command.
+#[derive(Parser, Debug)]
+pub struct LogArgs {
+    /// Print more messages, -vv also prints trace messages
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Print fewer messages, -qq only prints errors
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// When to color the output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// Also write the messages, with debug ones, to this file
+    #[arg(long, value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Bundle the Shiny Application on the specified directory
     Build(BuildArgs),
     /// Bundle and serve the Shiny Application on the specified directory
     Serve(ServeArgs),
+    /// Snapshot packages of a repository into a directory usable with --repo
+    Mirror(MirrorArgs),
 }
 
-#[derive(Parser, Debug)]
+/// Whether the bundle is built for developing the app or for deploying it.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Show the R console, the app's source code and Shiny's trace logs
+    Dev,
+    /// Give the app the whole page and keep the browser console quiet
+    Prod,
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Dev => write!(f, "dev"),
+            Mode::Prod => write!(f, "prod"),
+        }
+    }
+}
+
+/// How symbolic links in the app directory end up in the app archive.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Bundle the files and directories the links point to
+    #[default]
+    Follow,
+    /// Bundle the links themselves
+    Preserve,
+    /// Leave the links out
+    Skip,
+}
+
+/// Format of the app archive.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveFormat {
+    /// Gzip compressed tar
+    #[default]
+    Tgz,
+    /// Uncompressed tar, for servers compressing responses themselves
+    Tar,
+    /// XZ compressed tar, smaller but slower to build and extract
+    Xz,
+}
+
+impl ArchiveFormat {
+    /// Name of the archive in the bundle.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tgz => "app.tgz",
+            ArchiveFormat::Tar => "app.tar",
+            ArchiveFormat::Xz => "app.tar.xz",
+        }
+    }
+    /// Compression levels the format accepts, `None` when uncompressed.
+    pub fn levels(&self) -> Option<(u32, u32)> {
+        match self {
+            ArchiveFormat::Tgz | ArchiveFormat::Xz => Some((0, 9)),
+            ArchiveFormat::Tar => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveFormat::Tgz => write!(f, "tgz"),
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+/// How the app files are shipped to the browser.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppPackaging {
+    /// A single archive extracted by R
+    #[default]
+    Archive,
+    /// A read-only filesystem image mounted by webR
+    Image,
+    /// Every file on its own, listed in a manifest
+    Files,
+}
+
+/// How the progress of a build is reported.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Messages for people, on stderr
+    #[default]
+    Human,
+    /// Newline-delimited JSON events on stdout, messages stay on stderr
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
 pub struct BuildArgs {
-    /// Directory of the Shiny Application
-    #[arg(short, long, default_value = ".")]
-    appdir: PathBuf,
+    /// Directory of the Shiny Application. Give several directories to
+    /// bundle multiple apps together
+    #[arg(short, long = "appdir", default_value = ".", num_args = 1..)]
+    appdirs: Vec<PathBuf>,
 
     /// Directory to output the bundle
     #[arg(short, long, default_value = "dist")]
@@ -30,6 +164,208 @@ pub struct BuildArgs {
     /// Number of packages to download in parallel
     #[arg(short, long, default_value = "4")]
     parallel: usize,
+
+    /// Runtime mode, sets the defaults of the page template, the Shiny
+    /// display mode and logging
+    #[arg(short, long, value_enum, default_value_t = Mode::Prod)]
+    mode: Mode,
+
+    /// Version of the webR runtime the bundle is built for (e.g. v0.2.2).
+    /// Defaults to v0.2.2, or to the version of a local runtime given to
+    /// --vendor-webr
+    #[arg(long)]
+    webr_version: Option<String>,
+
+    /// R version of the package repository to download packages from
+    #[arg(long, default_value = "4.3")]
+    r_version: String,
+
+    /// Package repository to download packages from: an http(s) or file://
+    /// URL, a local directory or a tar archive with a snapshot of a
+    /// repository, laid out like bin/emscripten/contrib/<R_VERSION>/
+    #[arg(long, value_name = "URL|PATH", default_value = DEFAULT_REPO)]
+    repo: String,
+
+    /// Ship the webR runtime inside the bundle instead of loading it from
+    /// the CDN. Takes a webR version (defaults to --webr-version) whose
+    /// release is downloaded, a release archive or a local directory with
+    /// the webR distribution files
+    #[arg(long, value_name = "VERSION|PATH", num_args = 0..=1)]
+    vendor_webr: Option<Option<String>>,
+
+    /// Install the packages at build time into a filesystem image that the
+    /// page mounts as an R library, instead of installing them on every load
+    #[arg(long)]
+    library_image: bool,
+
+    /// Write a reproducible app archive: sorted entries, normalized
+    /// timestamps (from SOURCE_DATE_EPOCH), owners and permissions
+    #[arg(long)]
+    reproducible: bool,
+
+    /// How the app files are shipped. An image is mounted without
+    /// extracting anything but is read-only
+    #[arg(long, value_enum, default_value_t = AppPackaging::Archive)]
+    packaging: AppPackaging,
+
+    /// Format of the app archive
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Tgz)]
+    archive_format: ArchiveFormat,
+
+    /// Compression level of the app archive: 0-9 for tgz and xz
+    /// [default: the format's default]
+    #[arg(long)]
+    compression_level: Option<u32>,
+
+    /// Bundle the files of R projects that are ignored by default, like
+    /// renv/library, rsconnect/, packrat/ and *.Rproj
+    #[arg(long)]
+    no_default_ignores: bool,
+
+    /// How symbolic links in the app directory are bundled
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Follow)]
+    symlinks: SymlinkPolicy,
+
+    /// Warn about app files larger than this
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "10M")]
+    warn_file_size: u64,
+
+    /// Fail the build when an app file is larger than this
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// List the files and packages that would be bundled, and the estimated
+    /// download size, without writing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Fail the build when the bundle takes more than this to download,
+    /// e.g. 25M or 30MB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Write the size report as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    size_report: Option<PathBuf>,
+
+    /// How progress is reported
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    output: OutputFormat,
+
+    /// Configuration file [default: <APPDIR>/webr-bundle.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+
+    #[command(flatten)]
+    html: HtmlArgs,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+/// Settings of the HTTP client. Each of them can also be set in the
+/// `[http]` table of the configuration file.
+#[derive(Parser, Debug, Clone)]
+pub struct HttpArgs {
+    /// PEM file with the certificates of additional certificate authorities,
+    /// e.g. the one of a corporate proxy
+    #[arg(long, value_name = "FILE")]
+    ca_cert: Option<PathBuf>,
+
+    /// Seconds to wait for connections to servers [default: 30]
+    #[arg(long, value_name = "SECONDS")]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for a response or more data from servers before
+    /// failing [default: 60]
+    #[arg(long, value_name = "SECONDS")]
+    read_timeout: Option<u64>,
+
+    /// User-Agent header of the requests [default: webr-bundle/<VERSION>]
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// .netrc file with credentials by host [default: $NETRC or ~/.netrc]
+    #[arg(long, value_name = "FILE")]
+    netrc: Option<PathBuf>,
+}
+
+/// Settings of the generated `index.html`. Each of them can also be set in
+/// the `[html]` table of the configuration file.
+#[derive(Parser, Debug, Clone)]
+pub struct HtmlArgs {
+    /// Template for index.html: dev, production or the path to a template
+    /// file [default: the one matching --mode]
+    #[arg(long, value_name = "dev|production|FILE")]
+    html_template: Option<String>,
+
+    /// Title of the page
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Message shown while webR and the app are loading
+    #[arg(long)]
+    loading_message: Option<String>,
+
+    /// Favicon of the page, either a URL or a local file copied into the
+    /// bundle
+    #[arg(long)]
+    favicon: Option<String>,
+
+    /// CSS file included in the page
+    #[arg(long)]
+    custom_css: Option<PathBuf>,
+}
+
+/// Settings of the generated JavaScript runtime. Each of them can also be
+/// set in the `[runtime]` table of the configuration file.
+#[derive(Parser, Debug, Clone)]
+pub struct RuntimeArgs {
+    /// URL path the bundle is served from, e.g. /apps/my-app/. Relative
+    /// paths are resolved against the page [default: ./]
+    #[arg(long)]
+    base_path: Option<String>,
+
+    /// URL of the webR distribution, overrides the CDN URL derived from
+    /// --webr-version
+    #[arg(long)]
+    webr_url: Option<String>,
+
+    /// URL of the package repository, relative URLs are resolved against
+    /// install_packages.js, which sits next to the page [default: repo/]
+    #[arg(long)]
+    repo_url: Option<String>,
+
+    /// Directory of the webR filesystem the app is extracted to
+    /// [default: /home/web_user/app]
+    #[arg(long)]
+    mount_path: Option<String>,
+
+    /// Entry point of the app: a directory or an R file relative to the app
+    /// directory, or an R call returning a Shiny app (e.g. run_app()) which
+    /// is evaluated after sourcing the files in R/ [default: detected from
+    /// the app layout]
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Shiny display mode, either normal or showcase [default: showcase in
+    /// dev mode, normal in prod mode]
+    #[arg(long)]
+    display_mode: Option<String>,
+
+    /// Shiny option to set before running the app. Values are parsed as
+    /// JSON and used as strings otherwise. Can be repeated
+    #[arg(long = "shiny-option", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    shiny_options: Vec<(String, String)>,
+}
+
+fn parse_key_value(raw: &str) -> Result<(String, String), String> {
+    raw.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", raw))
 }
 
 #[derive(Parser, Debug)]
@@ -41,18 +377,235 @@ pub struct ServeArgs {
     /// Port to bind the server to
     #[arg(short, long, default_value = "8080")]
     port: u16,
+
+    /// Build the app before serving it and rebuild it when its files change
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Directory of the Shiny Application, used with --watch
+    #[arg(short, long, default_value = ".", requires = "watch")]
+    appdir: PathBuf,
+
+    /// Runtime mode of the bundle built with --watch
+    #[arg(short, long, value_enum, default_value_t = Mode::Dev, requires = "watch")]
+    mode: Mode,
+
+    /// Options of the build done by --watch, given after `--`, e.g.
+    /// `serve --watch -- --repo ./mirror --vendor-webr`
+    #[arg(last = true, value_name = "BUILD OPTIONS", requires = "watch")]
+    build_options: Vec<OsString>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MirrorArgs {
+    /// Packages to mirror with their dependencies: a comma-separated list of
+    /// names, a renv.lock file or a directory with one, or all
+    #[arg(long, value_name = "LIST|RENV.LOCK|all")]
+    packages: String,
+
+    /// Directory of the mirror. Running the command again resumes or
+    /// updates it
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Package repository to mirror, see build --repo
+    #[arg(long, value_name = "URL|PATH", default_value = DEFAULT_REPO)]
+    repo: String,
+
+    /// R version of the packages to mirror
+    #[arg(long, default_value = "4.3")]
+    r_version: String,
+
+    /// Number of packages to download in parallel
+    #[arg(short, long, default_value = "4")]
+    parallel: usize,
+
+    #[command(flatten)]
+    http: HttpArgs,
 }
 
 impl BuildArgs {
+    /// The first app directory, the only one unless bundling multiple apps.
     pub fn appdir(&self) -> &Path {
-        self.appdir.as_path()
+        self.appdirs[0].as_path()
+    }
+    pub fn appdirs(&self) -> &[PathBuf] {
+        &self.appdirs
     }
     pub fn outdir(&self) -> &Path {
         self.outdir.as_path()
     }
+    /// The same options, writing the bundle to `outdir`.
+    pub fn with_outdir(&self, outdir: impl Into<PathBuf>) -> Self {
+        Self {
+            outdir: outdir.into(),
+            ..self.clone()
+        }
+    }
     pub fn parallel(&self) -> usize {
         self.parallel
     }
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    pub fn bundle_options(&self) -> BundleOptions {
+        BundleOptions {
+            reproducible: self.reproducible,
+            packaging: self.packaging,
+            format: self.archive_format,
+            compression_level: self.compression_level,
+            symlinks: self.symlinks,
+            warn_file_size: self.warn_file_size,
+            max_file_size: self.max_file_size,
+            default_ignores: !self.no_default_ignores,
+            outdir: Some(self.outdir.clone()),
+        }
+    }
+    pub fn library_image(&self) -> bool {
+        self.library_image
+    }
+    pub fn r_version(&self) -> &str {
+        &self.r_version
+    }
+    pub fn repo(&self) -> &str {
+        &self.repo
+    }
+    /// The webR version the bundle targets. A version given to
+    /// `--vendor-webr` takes precedence over `--webr-version`. The version
+    /// of a local runtime is read with [`crate::webr::local_version`].
+    pub fn webr_version(&self) -> String {
+        match &self.vendor_webr {
+            Some(Some(spec)) if !Path::new(spec).exists() => normalize_version(spec),
+            _ => normalize_version(
+                self.requested_webr_version()
+                    .unwrap_or(DEFAULT_WEBR_VERSION),
+            ),
+        }
+    }
+    /// The version given to `--webr-version`, if any.
+    pub fn requested_webr_version(&self) -> Option<&str> {
+        self.webr_version.as_deref()
+    }
+    /// A local directory or release archive given to `--vendor-webr`.
+    pub fn local_webr(&self) -> Option<&Path> {
+        match &self.vendor_webr {
+            Some(Some(spec)) if Path::new(spec).exists() => Some(Path::new(spec)),
+            _ => None,
+        }
+    }
+    pub fn vendor_webr(&self) -> Option<&str> {
+        self.vendor_webr.as_ref().map(|spec| {
+            spec.as_deref().unwrap_or(
+                self.requested_webr_version()
+                    .unwrap_or(DEFAULT_WEBR_VERSION),
+            )
+        })
+    }
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+    pub fn size_report(&self) -> Option<&Path> {
+        self.size_report.as_deref()
+    }
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+    pub fn runtime(&self) -> &RuntimeArgs {
+        &self.runtime
+    }
+    pub fn html(&self) -> &HtmlArgs {
+        &self.html
+    }
+    pub fn http(&self) -> &HttpArgs {
+        &self.http
+    }
+}
+
+impl MirrorArgs {
+    pub fn packages(&self) -> &str {
+        &self.packages
+    }
+    pub fn out(&self) -> &Path {
+        self.out.as_path()
+    }
+    pub fn repo(&self) -> &str {
+        &self.repo
+    }
+    pub fn r_version(&self) -> &str {
+        &self.r_version
+    }
+    pub fn parallel(&self) -> usize {
+        self.parallel
+    }
+    pub fn http(&self) -> &HttpArgs {
+        &self.http
+    }
+}
+
+impl HttpArgs {
+    pub fn ca_cert(&self) -> Option<&Path> {
+        self.ca_cert.as_deref()
+    }
+    pub fn connect_timeout(&self) -> Option<u64> {
+        self.connect_timeout
+    }
+    pub fn read_timeout(&self) -> Option<u64> {
+        self.read_timeout
+    }
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+    pub fn netrc(&self) -> Option<&Path> {
+        self.netrc.as_deref()
+    }
+}
+
+impl HtmlArgs {
+    pub fn html_template(&self) -> Option<&str> {
+        self.html_template.as_deref()
+    }
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    pub fn loading_message(&self) -> Option<&str> {
+        self.loading_message.as_deref()
+    }
+    pub fn favicon(&self) -> Option<&str> {
+        self.favicon.as_deref()
+    }
+    pub fn custom_css(&self) -> Option<&Path> {
+        self.custom_css.as_deref()
+    }
+}
+
+impl RuntimeArgs {
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path.as_deref()
+    }
+    pub fn webr_url(&self) -> Option<&str> {
+        self.webr_url.as_deref()
+    }
+    pub fn repo_url(&self) -> Option<&str> {
+        self.repo_url.as_deref()
+    }
+    pub fn mount_path(&self) -> Option<&str> {
+        self.mount_path.as_deref()
+    }
+    pub fn entry(&self) -> Option<&str> {
+        self.entry.as_deref()
+    }
+    pub fn display_mode(&self) -> Option<&str> {
+        self.display_mode.as_deref()
+    }
+    pub fn shiny_options(&self) -> &[(String, String)] {
+        &self.shiny_options
+    }
 }
 
 impl ServeArgs {
@@ -62,6 +615,36 @@ impl ServeArgs {
     pub fn outdir(&self) -> &Path {
         self.outdir.as_path()
     }
+    pub fn watch(&self) -> bool {
+        self.watch
+    }
+    /// Options of the build done by `--watch`: the directories and the mode
+    /// of `serve`, followed by every build option given after `--`.
+    pub fn build_args(&self) -> BuildArgs {
+        let args: [OsString; 7] = [
+            "build".into(),
+            "--appdir".into(),
+            self.appdir.clone().into(),
+            "--outdir".into(),
+            self.outdir.clone().into(),
+            "--mode".into(),
+            self.mode.to_string().into(),
+        ];
+        BuildArgs::parse_from(args.into_iter().chain(self.build_options.iter().cloned()))
+    }
+}
+
+impl LogArgs {
+    /// Number of `-v` minus number of `-q`.
+    pub fn verbosity(&self) -> i8 {
+        self.verbose.min(i8::MAX as u8) as i8 - self.quiet.min(i8::MAX as u8) as i8
+    }
+    pub fn color(&self) -> ColorChoice {
+        self.color
+    }
+    pub fn log_file(&self) -> Option<&Path> {
+        self.log_file.as_deref()
+    }
 }
 
 impl Args {
@@ -71,4 +654,7 @@ impl Args {
     pub fn command(&self) -> &Command {
         &self.command
     }
+    pub fn log(&self) -> &LogArgs {
+        &self.log
+    }
 }
@@ -0,0 +1,169 @@
+use crate::errors::{BundlerResult, Error};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the configuration file looked up in the app directory.
+pub const CONFIG_FILE: &str = "webr-bundle.toml";
+
+/// Settings read from `webr-bundle.toml`. Every value is optional, options
+/// given on the command line take precedence over the ones in this file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub runtime: RuntimeConfig,
+    pub html: HtmlConfig,
+    pub http: HttpConfig,
+}
+
+/// Settings of the generated JavaScript runtime.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub base_path: Option<String>,
+    pub webr_url: Option<String>,
+    pub repo_url: Option<String>,
+    pub mount_path: Option<String>,
+    pub entry: Option<String>,
+    pub display_mode: Option<String>,
+    pub shiny_options: BTreeMap<String, toml::Value>,
+}
+
+/// Settings of the generated `index.html`. Paths are relative to the app
+/// directory.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HtmlConfig {
+    pub template: Option<String>,
+    pub title: Option<String>,
+    pub loading_message: Option<String>,
+    pub favicon: Option<String>,
+    pub custom_css: Option<String>,
+}
+
+/// Settings of the HTTP client. Builds of several apps merge the tables of
+/// every app.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HttpConfig {
+    pub ca_cert: Option<PathBuf>,
+    pub user_agent: Option<String>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub auth: Vec<AuthConfig>,
+}
+
+/// Credentials sent to the URLs starting with `url`: a bearer token, or a
+/// username and password. Secrets can be read from environment variables
+/// instead of being written in the file.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AuthConfig {
+    pub url: String,
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+}
+
+/// Sets `value` to `other` when unset. Fails with `name` when both are set
+/// to different values.
+fn merge_value<T: PartialEq>(
+    value: &mut Option<T>,
+    other: Option<T>,
+    name: &'static str,
+) -> Result<(), &'static str> {
+    match (&value, other) {
+        (Some(value), Some(other)) if *value != other => Err(name),
+        (None, other) => {
+            *value = other;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+impl HttpConfig {
+    /// Adds the settings of `other`. Fails with the name of the first
+    /// setting both tables give different values, credentials of the same
+    /// URL included.
+    pub fn merge(&mut self, other: HttpConfig) -> Result<(), &'static str> {
+        merge_value(&mut self.ca_cert, other.ca_cert, "ca-cert")?;
+        merge_value(&mut self.user_agent, other.user_agent, "user-agent")?;
+        merge_value(
+            &mut self.connect_timeout,
+            other.connect_timeout,
+            "connect-timeout",
+        )?;
+        merge_value(&mut self.read_timeout, other.read_timeout, "read-timeout")?;
+        for auth in other.auth {
+            match self.auth.iter().find(|kept| kept.url == auth.url) {
+                Some(kept) if *kept != auth => return Err("auth"),
+                Some(_) => {}
+                None => self.auth.push(auth),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Reads the configuration from `path` or, when no path is given, from
+    /// `webr-bundle.toml` in the app directory if it exists.
+    pub fn load(appdir: impl AsRef<Path>, path: Option<&Path>) -> BundlerResult<Self> {
+        let path: PathBuf = match path {
+            Some(path) => path.into(),
+            None => {
+                let default = appdir.as_ref().join(CONFIG_FILE);
+                if !default.exists() {
+                    return Ok(Config::default());
+                }
+                default
+            }
+        };
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(err) => return Err(Error::Config(path, err.to_string())),
+        };
+        toml::from_str(&raw).map_err(|err| Error::Config(path, err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.toml");
+        assert!(matches!(
+            Config::load(dir.path(), Some(&path)),
+            Err(Error::Config(config, _)) if config == path
+        ));
+        assert!(Config::load(dir.path(), None).is_ok());
+    }
+
+    #[test]
+    fn test_merge_http() {
+        let parse = |raw: &str| toml::from_str::<Config>(raw).unwrap().http;
+        let mut http =
+            parse("[http]\nread-timeout = 30\n[[http.auth]]\nurl = \"https://a\"\ntoken = \"x\"");
+        http.merge(parse(
+            "[http]\nread-timeout = 30\nuser-agent = \"ci\"\n[[http.auth]]\nurl = \"https://a\"\ntoken = \"x\"\n[[http.auth]]\nurl = \"https://b\"\ntoken = \"y\"",
+        ))
+        .unwrap();
+        assert_eq!(http.read_timeout, Some(30));
+        assert_eq!(http.user_agent.as_deref(), Some("ci"));
+        assert_eq!(http.auth.len(), 2);
+        assert_eq!(
+            http.clone().merge(parse("[http]\nread-timeout = 60")),
+            Err("read-timeout")
+        );
+        assert_eq!(
+            http.merge(parse("[[http.auth]]\nurl = \"https://b\"\ntoken = \"z\"")),
+            Err("auth")
+        );
+    }
+}
@@ -1,149 +1,322 @@
-use crate::errors::BundlerResult;
+use crate::errors::{BundlerResult, Error};
+use crate::events::{emit, millis, Event};
+use crate::progress::DownloadProgress;
+use crate::size::format_size;
 use colored::Colorize;
+use log::{debug, info, warn};
 
-use reqwest::{StatusCode, Url};
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
 };
 use tokio::sync::Semaphore;
 use tokio::time::Instant;
-use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tokio::{fs::File, io::AsyncWriteExt};
 
 use crate::renv::{Package, RenvLock};
 use crate::repo::VesionMatcher;
-
-const R_VERSION: &str = "4.3";
-const REPO: &str = "https://repo.r-wasm.org";
+use crate::repository::{contrib_path, PackageRepository};
+use sha2::Digest;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum Status {
-    Done,
-    Failed,
+    /// Downloaded, with the size of the tarball
+    Done(u64),
+    /// Downloaded by an earlier run, with the size of the tarball
+    Existing(u64),
+    /// The repository answered with this HTTP status
+    Failed(u16),
+}
+
+/// Checksum of a package tarball, as listed in the repository index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Md5(String),
+    Sha256(String),
 }
 
-struct InstallingQueue {
-    queue: Arc<Mutex<Vec<(String, std::time::Instant, Status)>>>,
+enum Hasher {
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
 }
 
-impl Clone for InstallingQueue {
-    fn clone(&self) -> Self {
-        Self {
-            queue: Arc::clone(&self.queue),
+impl Checksum {
+    fn hasher(&self) -> Hasher {
+        match self {
+            Checksum::Md5(_) => Hasher::Md5(md5::Md5::new()),
+            Checksum::Sha256(_) => Hasher::Sha256(sha2::Sha256::new()),
         }
     }
+    fn digest(&self) -> &str {
+        match self {
+            Checksum::Md5(digest) | Checksum::Sha256(digest) => digest,
+        }
+    }
+    /// Whether `data` has this checksum.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish() == self.digest()
+    }
 }
 
-struct PackageDownloader {
-    package: Package,
-    package_url: Url,
-    local_path: PathBuf,
-    client: reqwest::Client,
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Checksum::Md5(digest) => write!(f, "MD5 {}", digest),
+            Checksum::Sha256(digest) => write!(f, "SHA-256 {}", digest),
+        }
+    }
 }
 
-fn get_package_url(package: &Package) -> String {
-    let (package, version) = package.get_package();
-    let contrib = format!("{}/bin/emscripten/contrib/{}", REPO, R_VERSION);
-    let package_download = format!("{contrib}/{}_{}.tgz", package, version);
-    package_download
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+    /// The digest in lowercase hexadecimal, as written in indexes.
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
 }
 
-async fn create_download_dir(outdir: impl AsRef<Path>) -> BundlerResult<PathBuf> {
-    let dir_path = outdir
-        .as_ref()
-        .join("repo")
-        .join("bin")
-        .join("emscripten")
-        .join("contrib")
-        .join(R_VERSION);
-    tokio::fs::create_dir_all(&dir_path).await?;
-    Ok(dir_path)
+/// Directory of the bundled repository holding the package tarballs.
+pub fn contrib_dir(outdir: impl AsRef<Path>, r_version: &str) -> PathBuf {
+    outdir.as_ref().join("repo").join(contrib_path(r_version))
 }
 
-async fn create_package_tar_file(
-    download_path: &Path,
-    package: &str,
-    version: &str,
-) -> BundlerResult<File> {
-    let file = File::create(download_path.join(format!("{}_{}.tgz", package, version))).await?;
-    Ok(file)
+/// Size of the tarball at `path` when a previous run downloaded it, checking
+/// it against the checksum of the index when there is one. Tarballs are only
+/// written there once complete.
+pub(crate) async fn existing_tarball(path: &Path, checksum: Option<&Checksum>) -> Option<u64> {
+    let data = tokio::fs::read(path).await.ok()?;
+    match checksum {
+        Some(checksum) if !checksum.matches(&data) => None,
+        _ => Some(data.len() as u64),
+    }
 }
 
-impl PackageDownloader {
-    async fn new(
-        package: &Package,
-        outdir: impl AsRef<Path>,
-        client: reqwest::Client,
-    ) -> BundlerResult<Self> {
-        let local_path = create_download_dir(outdir.as_ref()).await?;
-        let package_url = get_package_url(package);
-        Ok(Self {
-            package: package.clone(),
-            package_url: Url::parse(&package_url)?,
-            local_path,
-            client,
-        })
-    }
-    async fn create_tar(&self) -> BundlerResult<File> {
-        let (package, version) = self.package.get_package();
-        create_package_tar_file(&self.local_path, package, version).await
+/// Streams the tarball of `package` into `local_path`, verifying its
+/// checksum. The tarball is written to a `.part` file first, renamed once
+/// complete and verified.
+async fn download_package(
+    repository: &PackageRepository,
+    package: &Package,
+    local_path: &Path,
+    progress: &DownloadProgress,
+) -> BundlerResult<Status> {
+    let (name, version) = package.get_package();
+    let path = local_path.join(format!("{}_{}.tgz", name, version));
+    if let Some(size) = existing_tarball(&path, package.checksum()).await {
+        progress.skip();
+        return Ok(Status::Existing(size));
     }
-    async fn download_package(&self) -> BundlerResult<Status> {
-        let mut res = self.client.get(self.package_url.clone()).send().await?;
-        match res.status() {
-            StatusCode::OK => {
-                // Create a file to stream the body of the response into
-                let mut tar = tokio::io::BufWriter::new(self.create_tar().await?);
-                while let Some(chunk) = res.chunk().await? {
-                    tar.write_all(&chunk).await?;
-                }
-                tar.flush().await?;
-                Ok(Status::Done)
+    let mut tarball = match repository.tarball(package).await {
+        Ok(tarball) => tarball,
+        Err(err) => {
+            progress.skip();
+            return match err {
+                Error::Download {
+                    status: Some(status),
+                    ..
+                } => Ok(Status::Failed(status)),
+                err => Err(err),
+            };
+        }
+    };
+    let bar = progress.start(&package.to_string(), tarball.size());
+    let url = repository.tarball_url(package);
+    let partial = path.with_extension("tgz.part");
+    let written = async {
+        let file = File::create(&partial)
+            .await
+            .map_err(Error::bundle(&partial))?;
+        let mut tar = tokio::io::BufWriter::new(file);
+        let mut hasher = package.checksum().map(Checksum::hasher);
+        let mut bytes = 0;
+        while let Some(chunk) = tarball
+            .chunk()
+            .await
+            .map_err(Error::download(&package.to_string(), &url))?
+        {
+            tar.write_all(&chunk)
+                .await
+                .map_err(Error::bundle(&partial))?;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&chunk);
             }
-            _ => Ok(Status::Failed),
+            bytes += chunk.len() as u64;
+            bar.inc(chunk.len() as u64);
         }
+        tar.flush().await.map_err(Error::bundle(&partial))?;
+        if let (Some(checksum), Some(hasher)) = (package.checksum(), hasher) {
+            let digest = hasher.finish();
+            if digest != checksum.digest() {
+                return Err(Error::Download {
+                    package: package.to_string(),
+                    url: url.clone(),
+                    status: None,
+                    reason: format!("expected the checksum {}, got {}", checksum, digest),
+                });
+            }
+        }
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(Error::bundle(&path))?;
+        BundlerResult::Ok(bytes)
     }
+    .await;
+    progress.finish(bar);
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    Ok(Status::Done(written?))
 }
 
 impl Package {
     async fn download(
         &self,
-        outdir: impl AsRef<Path>,
-        client: reqwest::Client,
+        repository: &PackageRepository,
+        local_path: &Path,
+        progress: &DownloadProgress,
     ) -> BundlerResult<Status> {
         let instant = std::time::Instant::now();
-        let downloader = PackageDownloader::new(self, outdir, client).await?;
-        let status = downloader.download_package().await?;
-        eprintln!(
-            "Downloaded {} in {}",
-            self.to_string().green(),
-            format!("{:.0?}", instant.elapsed()).cyan().italic()
-        );
+        let (package, version) = self.get_package();
+        let url = repository.tarball_url(self);
+        debug!("Downloading {}", url);
+        emit(Event::DownloadStarted {
+            package: package.to_string(),
+            version: version.to_string(),
+            url,
+        });
+        let status = download_package(repository, self, local_path, progress).await;
+        let elapsed_ms = millis(instant.elapsed());
+        let event = match &status {
+            Ok(Status::Done(bytes) | Status::Existing(bytes)) => Event::DownloadFinished {
+                package: package.to_string(),
+                version: version.to_string(),
+                bytes: *bytes,
+                elapsed_ms,
+            },
+            Ok(Status::Failed(code)) => Event::DownloadFailed {
+                package: package.to_string(),
+                version: version.to_string(),
+                error: format!("HTTP status {}", code),
+                elapsed_ms,
+            },
+            Err(err) => Event::DownloadFailed {
+                package: package.to_string(),
+                version: version.to_string(),
+                error: err.to_string(),
+                elapsed_ms,
+            },
+        };
+        emit(event);
+        let status = status?;
+        match status {
+            Status::Done(bytes) => info!(
+                "Downloaded {}, {} in {}",
+                self.to_string().green(),
+                format_size(bytes),
+                format!("{:.0?}", instant.elapsed()).cyan().italic()
+            ),
+            Status::Existing(bytes) => info!(
+                "{} is already downloaded, {}",
+                self.to_string().green(),
+                format_size(bytes)
+            ),
+            Status::Failed(code) => warn!(
+                "Failed to download {}, the repository answered {}",
+                self.to_string().red().bold(),
+                code
+            ),
+        }
         Ok(status)
     }
 }
 
 impl RenvLock {
+    /// Replaces the locked versions with the ones available for webR, drops
+    /// the unavailable packages and adds the missing dependencies.
+    pub async fn resolve(&mut self, repository: &PackageRepository) -> BundlerResult<()> {
+        let version_matcher = VesionMatcher::new(repository).await?;
+        version_matcher.sync_renv(self);
+        Ok(())
+    }
+    /// Size of the tarball of every package. `None` when the repository
+    /// does not report it.
+    pub async fn download_sizes(
+        &self,
+        repository: &PackageRepository,
+        parallel_requests: usize,
+    ) -> BundlerResult<Vec<(Package, Option<u64>)>> {
+        let semaphore = Arc::new(Semaphore::new(parallel_requests));
+        let tasks = self.packages().map(|package| {
+            let semaphore = Arc::clone(&semaphore);
+            async move {
+                let _permit = semaphore.acquire().await.expect("Semaphore is closed");
+                let size = repository.tarball_size(package).await?;
+                BundlerResult::Ok((package.clone(), size))
+            }
+        });
+        futures::future::join_all(tasks).await.into_iter().collect()
+    }
+    /// Resolves the packages and downloads them into the bundled repository.
+    /// Returns the packages that failed to download.
     pub async fn download(
         &mut self,
+        repository: &PackageRepository,
         outdir: impl AsRef<Path>,
         parallel_downloads: usize,
-    ) -> BundlerResult<()> {
-        let outdir: Arc<Path> = Arc::from(outdir.as_ref());
-        let client = reqwest::Client::new();
-        let version_matcher = VesionMatcher::new(client.clone()).await?;
-        version_matcher.sync_renv(self);
+    ) -> BundlerResult<Vec<Package>> {
+        self.resolve(repository).await?;
+        let local_path = contrib_dir(outdir, repository.r_version());
+        self.download_into(repository, local_path, parallel_downloads)
+            .await
+    }
+    /// Downloads the resolved packages into `dir`, keeping the tarballs an
+    /// earlier run already downloaded. Returns the packages that failed to
+    /// download.
+    pub async fn download_into(
+        &self,
+        repository: &PackageRepository,
+        dir: impl AsRef<Path>,
+        parallel_downloads: usize,
+    ) -> BundlerResult<Vec<Package>> {
+        let local_path = dir.as_ref();
+        tokio::fs::create_dir_all(local_path)
+            .await
+            .map_err(Error::bundle(local_path))?;
+        let local_path: Arc<Path> = Arc::from(local_path);
+        for package in self.packages() {
+            let (name, version) = package.get_package();
+            emit(Event::PackageResolved {
+                package: name.to_string(),
+                version: version.to_string(),
+                size: None,
+            });
+        }
         let mut download_tasks = Vec::with_capacity(self.packages().len());
         let semaphore = Arc::new(Semaphore::new(parallel_downloads));
         let start_time = Instant::now();
+        let progress = DownloadProgress::new(self.packages().len());
         for package in self.packages() {
-            let client = client.clone();
+            let repository = repository.clone();
+            let progress = progress.clone();
             let package = package.clone();
             let semaphore = Arc::clone(&semaphore);
-            let outdir = Arc::clone(&outdir);
+            let local_path = Arc::clone(&local_path);
             download_tasks.push(tokio::spawn(async move {
                 let _permit = semaphore.acquire().await.expect("Semaphore is closed");
-                BundlerResult::Ok((package.download(outdir, client).await?, package))
+                let status = package
+                    .download(&repository, &local_path, &progress)
+                    .await?;
+                BundlerResult::Ok((status, package))
             }));
         }
         let results = futures::future::join_all(download_tasks)
@@ -151,22 +324,23 @@ impl RenvLock {
             .into_iter()
             .map(|result| result.expect("Failed to join download tasks"))
             .collect::<Vec<_>>();
+        progress.clear();
         let mut failed_packages = Vec::new();
         let mut succeeded_packages = Vec::new();
         for result in results {
             let (status, package) = result?;
             match status {
-                Status::Done => succeeded_packages.push(package),
-                Status::Failed => failed_packages.push(package),
+                Status::Done(_) | Status::Existing(_) => succeeded_packages.push(package),
+                Status::Failed(_) => failed_packages.push(package),
             }
         }
-        eprintln!(
+        info!(
             "Downloaded {} packages successfully in {}",
             succeeded_packages.len().to_string().green(),
             format!("{:.0?}", start_time.elapsed()).cyan().italic()
         );
         if !failed_packages.is_empty() {
-            eprintln!(
+            warn!(
                 "Failed to download {} packages: {}",
                 failed_packages.len(),
                 failed_packages
@@ -176,30 +350,6 @@ impl RenvLock {
                     .join(", ")
             );
         }
-        Ok(())
+        Ok(failed_packages)
     }
 }
-
-fn get_packages_rds_url() -> String {
-    format!("{}/bin/emscripten/contrib/{}/PACKAGES.rds", REPO, R_VERSION)
-}
-
-pub async fn download_packages_rds(outdir: impl AsRef<Path>) -> BundlerResult<()> {
-    let client = reqwest::Client::new();
-    let res = client
-        .get(get_packages_rds_url())
-        .send()
-        .await?
-        .bytes()
-        .await?;
-    let outfile = outdir
-        .as_ref()
-        .join("repo")
-        .join("bin")
-        .join("emscripten")
-        .join("contrib")
-        .join(R_VERSION)
-        .join("PACKAGES.rds");
-    std::fs::write(outfile, res)?;
-    Ok(())
-}
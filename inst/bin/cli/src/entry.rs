@@ -0,0 +1,162 @@
+use crate::errors::{BundlerResult, Error};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// What the generated runtime passes to `shiny::runApp`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Entry {
+    /// A directory containing `app.R` or `ui.R`/`server.R`.
+    Dir(String),
+    /// An R file whose last expression is a Shiny app object.
+    File(String),
+    /// An R call returning a Shiny app object. The files in `R/` are
+    /// sourced before evaluating it, as in a package-style (golem) app.
+    Call(String),
+}
+
+impl From<&str> for Entry {
+    fn from(value: &str) -> Self {
+        let value = value.trim();
+        if value.ends_with(')') {
+            Entry::Call(value.to_string())
+        } else if value.ends_with(".R") || value.ends_with(".r") {
+            Entry::File(value.to_string())
+        } else {
+            Entry::Dir(value.trim_end_matches('/').to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Entry::Dir(dir) => write!(f, "directory {}", dir),
+            Entry::File(file) => write!(f, "file {}", file),
+            Entry::Call(call) => write!(f, "call {}", call),
+        }
+    }
+}
+
+fn relative(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+impl Entry {
+    /// Picks the entry point of the app in `appdir` from its layout:
+    /// Rhino apps are started from `app.R`, golem apps by calling
+    /// `run_app()`, anything else is run as a directory.
+    pub fn detect(appdir: impl AsRef<Path>) -> Self {
+        let appdir = appdir.as_ref();
+        if appdir.join("rhino.yml").exists() {
+            Entry::File("app.R".to_string())
+        } else if appdir.join("inst").join("golem-config.yml").exists() {
+            Entry::Call("run_app()".to_string())
+        } else {
+            Entry::Dir(".".to_string())
+        }
+    }
+    /// Checks that the files the entry point needs are part of the bundle.
+    /// `files` are the bundled paths, relative to `appdir`. A call must be
+    /// to a function defined in one of the files of `R/`, unless it calls
+    /// an installed package with `pkg::fun()`.
+    pub fn validate(
+        &self,
+        appdir: impl AsRef<Path>,
+        files: &BTreeSet<PathBuf>,
+    ) -> BundlerResult<()> {
+        let found = match self {
+            Entry::File(file) => files.contains(&relative(file)),
+            Entry::Dir(dir) => {
+                let dir = relative(dir);
+                files.contains(&dir.join("app.R"))
+                    || (files.contains(&dir.join("server.R"))
+                        && (files.contains(&dir.join("ui.R"))
+                            || files.contains(&dir.join("www").join("index.html"))))
+            }
+            Entry::Call(call) => {
+                let mut r_files = files.iter().filter(|file| {
+                    file.parent() == Some(Path::new("R"))
+                        && file.extension().is_some_and(|ext| ext == "R" || ext == "r")
+                });
+                match called_function(call) {
+                    Some(function) => {
+                        r_files.any(|file| defines(&appdir.as_ref().join(file), function))
+                    }
+                    None => call.contains("::"),
+                }
+            }
+        };
+        if found {
+            Ok(())
+        } else {
+            Err(Error::EntryNotFound(self.clone()))
+        }
+    }
+}
+
+/// Name of the function `call` calls, unless it's namespaced or not a
+/// plain function call.
+fn called_function(call: &str) -> Option<&str> {
+    let (function, _) = call.split_once('(')?;
+    let function = function.trim();
+    let valid = function
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '.' || c == '_')
+        && function.starts_with(|c: char| c.is_alphabetic() || c == '.');
+    valid.then_some(function)
+}
+
+/// Whether the R file at `path` assigns `function` at the start of a line.
+fn defines(path: &Path, function: &str) -> bool {
+    let Ok(source) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    source.lines().any(|line| {
+        line.trim_start()
+            .strip_prefix(function)
+            .map(str::trim_start)
+            .is_some_and(|rest| {
+                rest.starts_with("<-") || (rest.starts_with('=') && !rest.starts_with("=="))
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_from_str() {
+        assert_eq!(Entry::from("app/main.R"), Entry::File("app/main.R".into()));
+        assert_eq!(Entry::from("app/"), Entry::Dir("app".into()));
+        assert_eq!(Entry::from("run_app()"), Entry::Call("run_app()".into()));
+    }
+
+    #[test]
+    fn test_validate_entry() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(appdir.path().join("R")).unwrap();
+        std::fs::write(
+            appdir.path().join("R").join("run_app.R"),
+            "#' Run the app\nrun_app <- function(...) {\n  shinyApp(app_ui, app_server)\n}\n",
+        )
+        .unwrap();
+        let files = ["app/main.R", "ui.R", "server.R", "R/run_app.R"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<BTreeSet<_>>();
+        let validate = |entry: &str| Entry::from(entry).validate(appdir.path(), &files);
+        assert!(validate("./app/main.R").is_ok());
+        assert!(validate(".").is_ok());
+        assert!(validate("run_app()").is_ok());
+        assert!(validate("mypkg::run_app()").is_ok());
+        assert!(validate("start_app()").is_err());
+        assert!(validate("app.R").is_err());
+        assert!(validate("app").is_err());
+    }
+}
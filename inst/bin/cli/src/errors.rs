@@ -1,27 +1,347 @@
-use std::path::PathBuf;
+use crate::cli::ArchiveFormat;
+use crate::entry::Entry;
+use crate::size::format_size;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum Error {
     NoDistDir(PathBuf),
+    /// An IO error without context, built explicitly where the caller adds
+    /// it, like the URL of a download. Other IO errors name their file with
+    /// [`Error::bundle`] or a variant of their own.
     Io(std::io::Error),
     Decode(serde_json::Error),
     Request(reqwest::Error),
     UrlParse(url::ParseError),
     StripPrefix(std::path::StripPrefixError),
     PackageParseError(&'static str),
+    WebRFileNotFound(String),
+    IncompatibleWebR {
+        webr_version: String,
+        r_version: String,
+        expected: &'static str,
+    },
+    /// The webR release can't do what an option needs.
+    UnsupportedWebRFeature {
+        feature: &'static str,
+        webr_version: String,
+    },
+    /// The version of a vendored webR runtime differs from `--webr-version`.
+    WebRVersionMismatch {
+        path: PathBuf,
+        vendored: String,
+        requested: String,
+    },
+    /// The version of a vendored webR runtime can't be told from its files.
+    UnknownWebRVersion(PathBuf),
+    Template(String),
+    /// The configuration file can't be read or parsed.
+    Config(PathBuf, String),
+    /// The file given to `--log-file` can't be created.
+    LogFile(PathBuf, String),
+    EntryNotFound(Entry),
+    DuplicateAppName(String),
+    CompressionLevel(ArchiveFormat, u32),
+    PreservedSymlinks,
+    FileTooLarge {
+        path: PathBuf,
+        size: u64,
+        max_size: u64,
+    },
+    SizeBudgetExceeded {
+        size: u64,
+        max_size: u64,
+    },
+    /// An app's `renv.lock` can't be read or parsed. `missing` when there
+    /// is no such file.
+    Lockfile {
+        path: PathBuf,
+        missing: bool,
+        reason: String,
+    },
+    /// The package index of the repository can't be fetched or read.
+    Resolution {
+        url: String,
+        status: Option<u16>,
+        reason: String,
+    },
+    /// A package or a repository file can't be downloaded.
+    Download {
+        package: String,
+        url: String,
+        status: Option<u16>,
+        reason: String,
+    },
+    /// A file can't be read from the app directory or written to the bundle.
+    Bundle {
+        path: PathBuf,
+        reason: String,
+    },
+    /// A file or setting of the HTTP client can't be used. `what` names it,
+    /// e.g. `CA certificate ca.pem`.
+    HttpConfig {
+        what: String,
+        reason: String,
+    },
+    /// The server can't listen on its address.
+    Serve {
+        address: String,
+        reason: String,
+    },
+}
+
+/// Exit codes of the CLI, by kind of failure.
+pub mod exit_code {
+    /// Unexpected failures.
+    pub const OTHER: i32 = 1;
+    /// Invalid options or configuration, also used by clap for bad arguments.
+    pub const USAGE: i32 = 2;
+    pub const LOCKFILE: i32 = 3;
+    pub const RESOLUTION: i32 = 4;
+    pub const DOWNLOAD: i32 = 5;
+    pub const BUNDLE: i32 = 6;
+    pub const SIZE_BUDGET: i32 = 7;
+    pub const SERVE: i32 = 8;
+}
+
+impl Error {
+    /// Builds a lockfile error for the `renv.lock` at `path` that can't be
+    /// parsed.
+    pub fn lockfile<E: Display>(path: impl AsRef<Path>) -> impl FnOnce(E) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |err| Error::Lockfile {
+            path,
+            missing: false,
+            reason: err.to_string(),
+        }
+    }
+    /// Builds a lockfile error for the `renv.lock` at `path` that can't be
+    /// opened.
+    pub fn open_lockfile(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |err| Error::Lockfile {
+            path,
+            missing: err.kind() == std::io::ErrorKind::NotFound,
+            reason: err.to_string(),
+        }
+    }
+    /// Builds a bundle error for the file or directory at `path`.
+    pub fn bundle<E: Display>(path: impl AsRef<Path>) -> impl FnOnce(E) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |err| Error::Bundle {
+            path,
+            reason: err.to_string(),
+        }
+    }
+    /// Builds a resolution error for the package index at `url`.
+    pub fn resolution<E: Display>(url: &str) -> impl FnOnce(E) -> Error {
+        let url = url.to_string();
+        move |err| Error::Resolution {
+            url,
+            status: None,
+            reason: err.to_string(),
+        }
+    }
+    /// Builds a download error for `package`, fetched from `url`.
+    pub fn download<E: Display>(package: &str, url: &str) -> impl FnOnce(E) -> Error {
+        let (package, url) = (package.to_string(), url.to_string());
+        move |err| Error::Download {
+            package,
+            url,
+            status: None,
+            reason: err.to_string(),
+        }
+    }
+    /// Exit code of the CLI when failing with this error, one per kind of
+    /// failure as listed in [`exit_code`].
+    pub fn exit_code(&self) -> i32 {
+        use Error::*;
+        match self {
+            Config(..)
+            | LogFile(..)
+            | Template(_)
+            | IncompatibleWebR { .. }
+            | WebRVersionMismatch { .. }
+            | UnsupportedWebRFeature { .. }
+            | UnknownWebRVersion(_)
+            | DuplicateAppName(_)
+            | CompressionLevel(..)
+            | PreservedSymlinks
+            | HttpConfig { .. } => exit_code::USAGE,
+            Lockfile { .. } => exit_code::LOCKFILE,
+            Resolution { .. } | PackageParseError(_) => exit_code::RESOLUTION,
+            Download { .. } | Request(_) | WebRFileNotFound(_) => exit_code::DOWNLOAD,
+            Bundle { .. } | FileTooLarge { .. } | EntryNotFound(_) => exit_code::BUNDLE,
+            SizeBudgetExceeded { .. } => exit_code::SIZE_BUDGET,
+            Serve { .. } | NoDistDir(_) => exit_code::SERVE,
+            Io(_) | Decode(_) | UrlParse(_) | StripPrefix(_) => exit_code::OTHER,
+        }
+    }
+    /// What the user can do about the error, when we know.
+    pub fn hint(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            NoDistDir(_) => Some("Build the bundle first, or serve with --watch to build it"),
+            IncompatibleWebR { .. } => {
+                Some("Bundle packages for the R version of the webR release with --r-version, or pick another --webr-version")
+            }
+            UnsupportedWebRFeature { .. } => {
+                Some("Use webR v0.3.0 or later with --webr-version, or leave the option out")
+            }
+            WebRVersionMismatch { .. } => {
+                Some("Leave out --webr-version, or pass the version of the vendored runtime")
+            }
+            UnknownWebRVersion(_) => Some("Pass the version of the vendored runtime with --webr-version"),
+            CompressionLevel(..) => Some("Leave out --compression-level to use the format's default"),
+            PreservedSymlinks => Some("Use --packaging archive or another --symlinks policy"),
+            FileTooLarge { .. } => Some("Exclude it with .webrignore or raise --max-file-size"),
+            SizeBudgetExceeded { .. } => {
+                Some("Look at what takes the most space with --dry-run or --size-report")
+            }
+            EntryNotFound(_) => {
+                Some("Check that it exists and is not ignored, or set it with --entry")
+            }
+            Lockfile { missing: true, .. } => {
+                Some("Run renv::snapshot() in the app directory to write renv.lock")
+            }
+            Lockfile { missing: false, .. } => {
+                Some("Fix the lock file, or write it again with renv::snapshot()")
+            }
+            Resolution { .. } => {
+                Some("Check your network connection, --repo and that packages are published for --r-version")
+            }
+            Download { .. } | Request(_) => {
+                Some("Check your network connection, or retry with fewer --parallel downloads")
+            }
+            Serve { .. } => Some("Pick another --port, or stop the process using this one"),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Error::*;
         match self {
-            NoDistDir(path) => write!(f, "Error: The directory {:?} does not exists", path),
+            NoDistDir(path) => write!(f, "The directory {:?} does not exist", path),
             PackageParseError(err) => write!(f, "Package parse error: {}", err),
             StripPrefix(err) => write!(f, "Unable to normalize path: {}", err),
             Io(err) => write!(f, "IO error: {}", err),
             Decode(err) => write!(f, "JSON decode error: {}", err),
             Request(err) => write!(f, "Request error: {}", err),
             UrlParse(err) => write!(f, "URL parse error: {}", err),
+            WebRFileNotFound(file) => write!(f, "Missing webR file: {}", file),
+            IncompatibleWebR {
+                webr_version,
+                r_version,
+                expected,
+            } => write!(
+                f,
+                "webR {} is built against R {}, but packages are being bundled for R {}",
+                webr_version, expected, r_version
+            ),
+            UnsupportedWebRFeature {
+                feature,
+                webr_version,
+            } => write!(
+                f,
+                "{} mounts a filesystem image, which webR {} can't do",
+                feature, webr_version
+            ),
+            WebRVersionMismatch {
+                path,
+                vendored,
+                requested,
+            } => write!(
+                f,
+                "The webR runtime at {:?} is {}, but --webr-version is {}",
+                path, vendored, requested
+            ),
+            UnknownWebRVersion(path) => {
+                write!(
+                    f,
+                    "Unable to tell the version of the webR runtime at {:?}",
+                    path
+                )
+            }
+            Template(err) => write!(f, "Template error: {}", err),
+            Config(path, err) => write!(f, "Unable to read config file {:?}: {}", path, err),
+            LogFile(path, err) => write!(f, "Unable to write the log file {:?}: {}", path, err),
+            DuplicateAppName(name) => write!(
+                f,
+                "More than one app directory is named {:?}, app names must be unique",
+                name
+            ),
+            CompressionLevel(format, level) => match format.levels() {
+                Some((min, max)) => write!(
+                    f,
+                    "Compression level {} is out of range, {} archives take levels {} to {}",
+                    level, format, min, max
+                ),
+                None => write!(
+                    f,
+                    "{} archives are not compressed and take no compression level",
+                    format
+                ),
+            },
+            PreservedSymlinks => write!(f, "Symbolic links can only be preserved in app archives"),
+            FileTooLarge {
+                path,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "{:?} is {}, larger than the limit of {}",
+                path,
+                format_size(*size),
+                format_size(*max_size)
+            ),
+            SizeBudgetExceeded { size, max_size } => write!(
+                f,
+                "The bundle takes {} to download, more than the budget of {}",
+                format_size(*size),
+                format_size(*max_size)
+            ),
+            EntryNotFound(entry) => write!(
+                f,
+                "The app's entry point ({}) is not part of the bundle",
+                entry
+            ),
+            Lockfile { path, reason, .. } => {
+                write!(f, "Unable to read the lockfile {:?}: {}", path, reason)
+            }
+            Resolution {
+                url,
+                status: Some(status),
+                ..
+            } => write!(
+                f,
+                "Unable to fetch the package index {}: the repository answered HTTP {}",
+                url, status
+            ),
+            Resolution { url, reason, .. } => {
+                write!(f, "Unable to fetch the package index {}: {}", url, reason)
+            }
+            Download {
+                package,
+                url,
+                status: Some(status),
+                ..
+            } => write!(
+                f,
+                "Unable to download {} from {}: the repository answered HTTP {}",
+                package, url, status
+            ),
+            Download {
+                package,
+                url,
+                reason,
+                ..
+            } => write!(f, "Unable to download {} from {}: {}", package, url, reason),
+            Bundle { path, reason } => write!(f, "Unable to bundle {:?}: {}", path, reason),
+            HttpConfig { what, reason } => write!(f, "Unable to use the {}: {}", what, reason),
+            Serve { address, reason } => write!(f, "Unable to serve on {}: {}", address, reason),
         }
     }
 }
@@ -38,12 +358,6 @@ impl From<url::ParseError> for Error {
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Error::Io(err)
-    }
-}
-
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
         Error::Decode(err)
@@ -59,3 +373,30 @@ impl From<reqwest::Error> for Error {
 impl std::error::Error for Error {}
 
 pub type BundlerResult<T> = Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renv::RenvLock;
+
+    #[test]
+    fn test_missing_lockfile() {
+        let appdir = tempfile::tempdir().unwrap();
+        let err = RenvLock::read_from_file(appdir.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&appdir.path().join("renv.lock").display().to_string()));
+        assert_eq!(err.exit_code(), exit_code::LOCKFILE);
+        assert!(err.hint().unwrap().contains("renv::snapshot()"));
+        assert!(matches!(err, Error::Lockfile { missing: true, .. }));
+    }
+
+    #[test]
+    fn test_invalid_lockfile() {
+        let appdir = tempfile::tempdir().unwrap();
+        std::fs::write(appdir.path().join("renv.lock"), "{ \"Packages\": [").unwrap();
+        let err = RenvLock::read_from_file(appdir.path()).unwrap_err();
+        assert!(matches!(err, Error::Lockfile { missing: false, .. }));
+        assert!(err.hint().unwrap().starts_with("Fix the lock file"));
+    }
+}
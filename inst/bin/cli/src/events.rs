@@ -0,0 +1,150 @@
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Switches `emit` to printing events to stdout, one JSON object per line.
+pub fn set_json_output(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// What a path added to the bundle is.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// Something that happened during a build, for tools driving the CLI with
+/// `--output json`. Serialized with its name in the `event` field.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    BuildStarted {
+        outdir: PathBuf,
+        apps: usize,
+    },
+    /// The files of an app are being bundled.
+    AppStarted {
+        app: String,
+        appdir: PathBuf,
+    },
+    /// A file, directory or symlink went into the bundle. Only files have
+    /// a `size`.
+    FileAdded {
+        path: PathBuf,
+        kind: FileKind,
+        size: Option<u64>,
+    },
+    /// A file left out of a dry run by the ignore rules.
+    FileExcluded {
+        path: String,
+        reason: String,
+    },
+    /// A package that will be downloaded. `size` is only known in dry runs.
+    PackageResolved {
+        package: String,
+        version: String,
+        size: Option<u64>,
+    },
+    /// A package of `renv.lock` that is not available for webR.
+    PackageUnavailable {
+        package: String,
+        version: String,
+    },
+    DownloadStarted {
+        package: String,
+        version: String,
+        url: String,
+    },
+    DownloadFinished {
+        package: String,
+        version: String,
+        bytes: u64,
+        elapsed_ms: u64,
+    },
+    DownloadFailed {
+        package: String,
+        version: String,
+        error: String,
+        elapsed_ms: u64,
+    },
+    Warning {
+        message: String,
+    },
+    /// Summary of a build, `size` being what the bundle takes to download.
+    BuildFinished {
+        apps: usize,
+        packages: usize,
+        failed_packages: Vec<String>,
+        size: u64,
+        uncompressed_size: u64,
+        elapsed_ms: u64,
+    },
+    /// Summary of a dry run.
+    DryRunFinished {
+        files: usize,
+        packages: usize,
+        download_size: u64,
+    },
+    /// The error the CLI exits with.
+    Error {
+        message: String,
+        hint: Option<String>,
+        exit_code: i32,
+    },
+}
+
+/// Milliseconds in `duration`, as events report timings.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Prints `event` when the output is JSON, does nothing otherwise.
+pub fn emit(event: Event) {
+    if !json_output() {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(&event) {
+        let mut stdout = std::io::stdout().lock();
+        // A closed pipe must not stop the build
+        let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Event::DownloadFinished {
+            package: "shiny".to_string(),
+            version: "1.7.5".to_string(),
+            bytes: 4096,
+            elapsed_ms: 120,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"download_finished","package":"shiny","version":"1.7.5","bytes":4096,"elapsed_ms":120}"#
+        );
+        let event = Event::FileAdded {
+            path: PathBuf::from("www"),
+            kind: FileKind::Dir,
+            size: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"file_added","path":"www","kind":"dir","size":null}"#
+        );
+    }
+}
@@ -1,10 +1,297 @@
-use crate::errors::BundlerResult;
-use std::path::Path;
+use crate::cli::{HtmlArgs, Mode};
+use crate::config::HtmlConfig;
+use crate::errors::{BundlerResult, Error};
+use crate::template::{html_escape, render, render_known, uses_variable, Variables};
+use std::path::{Path, PathBuf};
 
-const INDEX_HTML_FILE: &str = include_str!("index.html");
+const DEV_TEMPLATE: &str = include_str!("index.html");
+const PRODUCTION_TEMPLATE: &str = include_str!("production.html");
+const LANDING_TEMPLATE: &str = include_str!("landing.html");
 
-pub fn write_index_html_file(outdir: impl AsRef<Path>) -> BundlerResult<()> {
+const DEFAULT_TITLE: &str = "Standalone Shiny on webR demo";
+const DEFAULT_LANDING_TITLE: &str = "Shiny Apps";
+const DEFAULT_LOADING_MESSAGE: &str = "Please wait, webR is loading";
+const SCRIPTS: &[&str] = &["shiny.js"];
+
+/// Template `index.html` is rendered from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HtmlTemplate {
+    /// Built-in page with an R console below the app.
+    Dev,
+    /// Built-in page where the app takes the whole window.
+    Production,
+    /// User provided template file.
+    File(PathBuf),
+}
+
+impl From<&str> for HtmlTemplate {
+    fn from(value: &str) -> Self {
+        match value {
+            "dev" => HtmlTemplate::Dev,
+            "production" => HtmlTemplate::Production,
+            path => HtmlTemplate::File(path.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Favicon {
+    Url(String),
+    File(PathBuf),
+}
+
+/// Everything `index.html` is rendered with.
+#[derive(Debug, Clone)]
+pub struct HtmlContext {
+    template: HtmlTemplate,
+    title: String,
+    loading_message: String,
+    favicon: Option<Favicon>,
+    custom_css: Option<PathBuf>,
+}
+
+fn is_url(value: &str) -> bool {
+    ["http://", "https://", "data:"]
+        .iter()
+        .any(|prefix| value.starts_with(prefix))
+}
+
+impl HtmlContext {
+    /// Builds the context from the command line and the configuration file,
+    /// command line options taking precedence. Paths in the configuration
+    /// file are relative to the app directory. The template defaults to the
+    /// one matching the runtime mode.
+    pub fn new(mode: Mode, args: &HtmlArgs, config: &HtmlConfig, appdir: impl AsRef<Path>) -> Self {
+        let from_config = |path: &str| appdir.as_ref().join(path);
+        let template = match (args.html_template(), config.template.as_deref()) {
+            (Some(template), _) => template.into(),
+            (None, Some(template)) => match HtmlTemplate::from(template) {
+                HtmlTemplate::File(path) => {
+                    HtmlTemplate::File(from_config(&path.to_string_lossy()))
+                }
+                template => template,
+            },
+            (None, None) => match mode {
+                Mode::Dev => HtmlTemplate::Dev,
+                Mode::Prod => HtmlTemplate::Production,
+            },
+        };
+        let favicon = match (args.favicon(), config.favicon.as_deref()) {
+            (Some(favicon), _) if is_url(favicon) => Some(Favicon::Url(favicon.to_string())),
+            (Some(favicon), _) => Some(Favicon::File(favicon.into())),
+            (None, Some(favicon)) if is_url(favicon) => Some(Favicon::Url(favicon.to_string())),
+            (None, Some(favicon)) => Some(Favicon::File(from_config(favicon))),
+            (None, None) => None,
+        };
+        let custom_css = match (args.custom_css(), config.custom_css.as_deref()) {
+            (Some(css), _) => Some(css.to_path_buf()),
+            (None, Some(css)) => Some(from_config(css)),
+            (None, None) => None,
+        };
+        Self {
+            template,
+            title: args
+                .title()
+                .or(config.title.as_deref())
+                .unwrap_or(DEFAULT_TITLE)
+                .to_string(),
+            loading_message: args
+                .loading_message()
+                .or(config.loading_message.as_deref())
+                .unwrap_or(DEFAULT_LOADING_MESSAGE)
+                .to_string(),
+            favicon,
+            custom_css,
+        }
+    }
+    /// Copies a local favicon into the bundle and returns the tag linking it.
+    fn favicon_tag(&self, outdir: &Path) -> BundlerResult<String> {
+        let href = match &self.favicon {
+            None => return Ok(String::new()),
+            Some(Favicon::Url(url)) => url.clone(),
+            Some(Favicon::File(path)) => {
+                let name = match path.extension() {
+                    Some(extension) => format!("favicon.{}", extension.to_string_lossy()),
+                    None => "favicon".to_string(),
+                };
+                std::fs::copy(path, outdir.join(&name)).map_err(Error::bundle(path))?;
+                name
+            }
+        };
+        Ok(format!(
+            r#"<link rel="icon" href="{}">"#,
+            html_escape(&href)
+        ))
+    }
+    fn custom_css_tag(&self) -> BundlerResult<String> {
+        match &self.custom_css {
+            None => Ok(String::new()),
+            Some(path) => {
+                // A literal `</style` would end the element early
+                let css = std::fs::read_to_string(path)
+                    .map_err(Error::bundle(path))?
+                    .replace("</style", "<\\/style");
+                Ok(format!("<style>\n{}\n</style>", css))
+            }
+        }
+    }
+    /// Source of the template. A template file has to reference
+    /// `{{ scripts }}`, without it the page never loads the app.
+    fn template_source(&self) -> BundlerResult<String> {
+        match &self.template {
+            HtmlTemplate::Dev => Ok(DEV_TEMPLATE.to_string()),
+            HtmlTemplate::Production => Ok(PRODUCTION_TEMPLATE.to_string()),
+            HtmlTemplate::File(path) => {
+                let template = std::fs::read_to_string(path).map_err(Error::bundle(path))?;
+                if !uses_variable(&template, "scripts") {
+                    return Err(Error::Template(format!(
+                        "{:?} doesn't include {{{{ scripts }}}}, which loads the app",
+                        path
+                    )));
+                }
+                Ok(template)
+            }
+        }
+    }
+    /// Fails when the template can't be rendered, so a broken template is
+    /// reported before anything is downloaded.
+    pub fn check(&self) -> BundlerResult<()> {
+        self.template_source().map(|_| ())
+    }
+    fn variables(&self, outdir: &Path) -> BundlerResult<Variables> {
+        let scripts = SCRIPTS
+            .iter()
+            .map(|script| format!(r#"<script src="{}"></script>"#, script))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(Variables::new()
+            .html("title", &self.title)
+            .html("loading_message", &self.loading_message)
+            .raw("favicon", self.favicon_tag(outdir)?)
+            .raw("custom_css", self.custom_css_tag()?)
+            .raw("scripts", scripts))
+    }
+}
+
+pub fn write_index_html_file(outdir: impl AsRef<Path>, context: &HtmlContext) -> BundlerResult<()> {
+    let template = context.template_source()?;
+    let variables = context.variables(outdir.as_ref())?;
+    let html = match &context.template {
+        HtmlTemplate::File(_) => render_known(&template, &variables)?,
+        _ => render(&template, &variables)?,
+    };
     let outfile = outdir.as_ref().join("index.html");
-    std::fs::write(outfile, INDEX_HTML_FILE)?;
-    Ok(())
+    std::fs::write(&outfile, html).map_err(Error::bundle(&outfile))
+}
+
+/// Writes the `index.html` of a multi-app bundle, linking to the page of
+/// each app. `apps` are pairs of app names and page directories.
+pub fn write_landing_page(
+    outdir: impl AsRef<Path>,
+    title: Option<&str>,
+    apps: &[(String, String)],
+) -> BundlerResult<()> {
+    let links = apps
+        .iter()
+        .map(|(name, path)| {
+            format!(
+                r#"<li><a href="{}">{}</a></li>"#,
+                html_escape(path),
+                html_escape(name)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let variables = Variables::new()
+        .html("title", title.unwrap_or(DEFAULT_LANDING_TITLE))
+        .raw("apps", links);
+    let outfile = outdir.as_ref().join("index.html");
+    std::fs::write(&outfile, render(LANDING_TEMPLATE, &variables)?).map_err(Error::bundle(&outfile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn html_args(args: &[&str]) -> HtmlArgs {
+        HtmlArgs::parse_from(std::iter::once("html").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_html_context() {
+        let config = HtmlConfig {
+            template: Some("page.html".to_string()),
+            title: Some("From config".to_string()),
+            custom_css: Some("style.css".to_string()),
+            ..Default::default()
+        };
+        let context = HtmlContext::new(Mode::Dev, &html_args(&[]), &config, "app");
+        assert_eq!(
+            context.template,
+            HtmlTemplate::File(Path::new("app").join("page.html"))
+        );
+        assert_eq!(context.title, "From config");
+        assert_eq!(context.custom_css, Some(Path::new("app").join("style.css")));
+
+        let args = html_args(&["--html-template", "production", "--title", "From args"]);
+        let context = HtmlContext::new(Mode::Dev, &args, &config, "app");
+        assert_eq!(context.template, HtmlTemplate::Production);
+        assert_eq!(context.title, "From args");
+        let context = HtmlContext::new(Mode::Prod, &html_args(&[]), &HtmlConfig::default(), "app");
+        assert_eq!(context.template, HtmlTemplate::Production);
+        assert_eq!(context.loading_message, DEFAULT_LOADING_MESSAGE);
+    }
+
+    #[test]
+    fn test_production_template() {
+        let outdir = tempfile::tempdir().unwrap();
+        let args = html_args(&[
+            "--title",
+            "<My app>",
+            "--favicon",
+            "https://example.com/icon.png",
+        ]);
+        let context = HtmlContext::new(Mode::Prod, &args, &HtmlConfig::default(), ".");
+        write_index_html_file(outdir.path(), &context).unwrap();
+        let html = std::fs::read_to_string(outdir.path().join("index.html")).unwrap();
+        assert!(html.contains("<title>&lt;My app&gt;</title>"));
+        assert!(html.contains(r#"<link rel="icon" href="https://example.com/icon.png">"#));
+        assert!(html.contains(r#"<script src="shiny.js"></script>"#));
+        assert!(!html.contains("{{"));
+    }
+
+    #[test]
+    fn test_file_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("page.html");
+        std::fs::write(
+            &template,
+            "<title>{{ title }}</title><p>{{ user.name }}</p>{{ custom_css }}{{ scripts }}",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("style.css"), "p { color: red }").unwrap();
+        let args = html_args(&["--html-template", template.to_str().unwrap()]);
+        let config = HtmlConfig {
+            custom_css: Some("style.css".to_string()),
+            ..Default::default()
+        };
+        let context = HtmlContext::new(Mode::Prod, &args, &config, dir.path());
+        context.check().unwrap();
+        write_index_html_file(dir.path(), &context).unwrap();
+        let html = std::fs::read_to_string(dir.path().join("index.html")).unwrap();
+        assert!(html.contains("<p>{{ user.name }}</p>"));
+        assert!(html.contains("<style>\np { color: red }\n</style>"));
+        assert!(html.contains(r#"<script src="shiny.js"></script>"#));
+
+        std::fs::write(&template, "<title>{{ title }}</title>").unwrap();
+        assert!(matches!(context.check(), Err(Error::Template(_))));
+
+        let args = html_args(&["--custom-css", "missing.css"]);
+        let context = HtmlContext::new(Mode::Prod, &args, &HtmlConfig::default(), ".");
+        assert!(matches!(
+            write_index_html_file(dir.path(), &context),
+            Err(Error::Bundle { path, .. }) if path == Path::new("missing.css")
+        ));
+    }
 }
@@ -0,0 +1,309 @@
+use crate::cli::HttpArgs;
+use crate::config::{AuthConfig, HttpConfig};
+use crate::errors::{BundlerResult, Error};
+use bytes::Bytes;
+use reqwest::{Certificate, RequestBuilder, Response, Url};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// User-Agent sent with every request unless `--user-agent` is given.
+pub const DEFAULT_USER_AGENT: &str = concat!("webr-bundle/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Auth {
+    Bearer(String),
+    Basic(String, Option<String>),
+}
+
+/// Credentials sent to the URLs starting with `prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UrlCredentials {
+    prefix: String,
+    auth: Auth,
+}
+
+/// Credentials of a `.netrc` file, by host. `None` is the `default` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NetrcCredentials {
+    machine: Option<String>,
+    login: String,
+    password: Option<String>,
+}
+
+/// Parses the `machine`, `default`, `login` and `password` entries of a
+/// `.netrc` file, skipping the other tokens. A `macdef` line starts a macro
+/// running to the next blank line, whose body is skipped.
+fn parse_netrc(raw: &str) -> Vec<NetrcCredentials> {
+    let mut entries: Vec<NetrcCredentials> = Vec::new();
+    let mut words = Vec::new();
+    let mut in_macro = false;
+    for line in raw.lines() {
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+        for word in line.split_whitespace() {
+            if word == "macdef" {
+                in_macro = true;
+                break;
+            }
+            words.push(word);
+        }
+    }
+    let mut tokens = words.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push(NetrcCredentials {
+                machine: tokens.next().map(str::to_string),
+                login: String::new(),
+                password: None,
+            }),
+            "default" => entries.push(NetrcCredentials {
+                machine: None,
+                login: String::new(),
+                password: None,
+            }),
+            "login" | "password" => {
+                let (Some(entry), Some(value)) = (entries.last_mut(), tokens.next()) else {
+                    continue;
+                };
+                if token == "login" {
+                    entry.login = value.to_string();
+                } else {
+                    entry.password = Some(value.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    entries
+}
+
+/// The `.netrc` file to read: `--netrc`, then `NETRC`, then `~/.netrc` if
+/// it exists.
+fn netrc_path(args: &HttpArgs) -> Option<PathBuf> {
+    if let Some(path) = args.netrc() {
+        return Some(path.to_path_buf());
+    }
+    if let Some(path) = std::env::var_os("NETRC") {
+        return Some(path.into());
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".netrc")).filter(|path| path.is_file())
+}
+
+fn read_netrc(path: &Path) -> BundlerResult<Vec<NetrcCredentials>> {
+    match std::fs::read_to_string(path) {
+        Ok(raw) => Ok(parse_netrc(&raw)),
+        Err(err) => Err(Error::HttpConfig {
+            what: format!(".netrc file {}", path.display()),
+            reason: err.to_string(),
+        }),
+    }
+}
+
+/// Reads a value of the configuration file given either directly or as the
+/// name of an environment variable holding it.
+fn config_secret(value: &Option<String>, env: &Option<String>) -> BundlerResult<Option<String>> {
+    match (value, env) {
+        (Some(value), _) => Ok(Some(value.clone())),
+        (None, Some(name)) => std::env::var(name)
+            .map(Some)
+            .map_err(|_| Error::HttpConfig {
+                what: format!("environment variable {}", name),
+                reason: "it is not set".to_string(),
+            }),
+        (None, None) => Ok(None),
+    }
+}
+
+fn url_credentials(auth: &AuthConfig) -> BundlerResult<UrlCredentials> {
+    let auth_kind = match config_secret(&auth.token, &auth.token_env)? {
+        Some(token) => Auth::Bearer(token),
+        None => match &auth.username {
+            Some(username) => Auth::Basic(
+                username.clone(),
+                config_secret(&auth.password, &auth.password_env)?,
+            ),
+            None => {
+                return Err(Error::HttpConfig {
+                    what: format!("credentials of {}", auth.url),
+                    reason: "they need a token or a username".to_string(),
+                })
+            }
+        },
+    };
+    Ok(UrlCredentials {
+        prefix: auth.url.clone(),
+        auth: auth_kind,
+    })
+}
+
+/// The HTTP client every request of a build goes through. Proxies are read
+/// from `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and `NO_PROXY`.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    read_timeout: Duration,
+    credentials: Arc<[UrlCredentials]>,
+    netrc: Arc<[NetrcCredentials]>,
+}
+
+impl HttpClient {
+    /// Builds the client from the command line and the `[http]` table of
+    /// the configuration file, command line options taking precedence.
+    /// Paths in the configuration file are relative to the app directory.
+    pub fn new(
+        args: &HttpArgs,
+        config: &HttpConfig,
+        appdir: impl AsRef<Path>,
+    ) -> BundlerResult<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(
+                args.user_agent()
+                    .or(config.user_agent.as_deref())
+                    .unwrap_or(DEFAULT_USER_AGENT),
+            )
+            .connect_timeout(Duration::from_secs(
+                args.connect_timeout()
+                    .or(config.connect_timeout)
+                    .unwrap_or(30),
+            ));
+        let ca_cert = match (args.ca_cert(), &config.ca_cert) {
+            (Some(path), _) => Some(path.to_path_buf()),
+            (None, Some(path)) => Some(appdir.as_ref().join(path)),
+            (None, None) => None,
+        };
+        if let Some(path) = ca_cert {
+            let http_config_error = |err: &dyn std::fmt::Display| Error::HttpConfig {
+                what: format!("CA certificate {}", path.display()),
+                reason: err.to_string(),
+            };
+            let pem = std::fs::read(&path).map_err(|err| http_config_error(&err))?;
+            let certificate = Certificate::from_pem(&pem).map_err(|err| http_config_error(&err))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let netrc = match netrc_path(args) {
+            Some(path) => read_netrc(&path)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: Duration::from_secs(
+                args.read_timeout().or(config.read_timeout).unwrap_or(60),
+            ),
+            credentials: config
+                .auth
+                .iter()
+                .map(url_credentials)
+                .collect::<BundlerResult<_>>()?,
+            netrc: netrc.into(),
+        })
+    }
+    /// Credentials of the configuration file with the longest matching
+    /// prefix, then the `.netrc` entry of the host, then its default entry.
+    fn auth(&self, url: &str) -> Option<Auth> {
+        let configured = self
+            .credentials
+            .iter()
+            .filter(|credentials| url.starts_with(&credentials.prefix))
+            .max_by_key(|credentials| credentials.prefix.len());
+        if let Some(credentials) = configured {
+            return Some(credentials.auth.clone());
+        }
+        let host = Url::parse(url).ok()?.host_str()?.to_string();
+        self.netrc
+            .iter()
+            .find(|entry| entry.machine.as_deref() == Some(host.as_str()))
+            .or_else(|| self.netrc.iter().find(|entry| entry.machine.is_none()))
+            .map(|entry| Auth::Basic(entry.login.clone(), entry.password.clone()))
+    }
+    fn with_auth(&self, request: RequestBuilder, url: &str) -> RequestBuilder {
+        match self.auth(url) {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic(username, password)) => request.basic_auth(username, password),
+            None => request,
+        }
+    }
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.with_auth(self.client.get(url), url)
+    }
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.with_auth(self.client.head(url), url)
+    }
+    /// Sends `request`, failing when the server doesn't answer within the
+    /// read timeout. The body is then read with [`HttpClient::chunk`].
+    pub async fn send(&self, request: RequestBuilder) -> BundlerResult<Response> {
+        match tokio::time::timeout(self.read_timeout, request.send()).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no response received for {:?}", self.read_timeout),
+            ))),
+        }
+    }
+    /// Next chunk of the body of `res`, failing when the server sends
+    /// nothing for longer than the read timeout.
+    pub async fn chunk(&self, res: &mut Response) -> BundlerResult<Option<Bytes>> {
+        match tokio::time::timeout(self.read_timeout, res.chunk()).await {
+            Ok(chunk) => Ok(chunk?),
+            Err(_) => Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no data received for {:?}", self.read_timeout),
+            ))),
+        }
+    }
+    /// The whole body of `res`, read with the read timeout.
+    pub async fn body(&self, mut res: Response) -> BundlerResult<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk(&mut res).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        let netrc = parse_netrc(
+            "machine repo.example.com login ci password secret\n\
+             macdef init\n\
+             machine other.example.com login macro password leaked\n\
+             \n\
+             default login anonymous",
+        );
+        let client = HttpClient {
+            client: reqwest::Client::new(),
+            read_timeout: Duration::from_secs(1),
+            credentials: vec![
+                UrlCredentials {
+                    prefix: "https://cran.example.com/".to_string(),
+                    auth: Auth::Bearer("generic".to_string()),
+                },
+                UrlCredentials {
+                    prefix: "https://cran.example.com/private/".to_string(),
+                    auth: Auth::Bearer("private".to_string()),
+                },
+            ]
+            .into(),
+            netrc: netrc.into(),
+        };
+        assert_eq!(
+            client.auth("https://cran.example.com/private/PACKAGES.gz"),
+            Some(Auth::Bearer("private".to_string()))
+        );
+        assert_eq!(
+            client.auth("https://repo.example.com/PACKAGES.gz"),
+            Some(Auth::Basic("ci".to_string(), Some("secret".to_string())))
+        );
+        assert_eq!(
+            client.auth("https://other.example.com/"),
+            Some(Auth::Basic("anonymous".to_string(), None))
+        );
+    }
+}
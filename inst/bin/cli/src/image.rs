@@ -0,0 +1,70 @@
+use crate::errors::{BundlerResult, Error};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Debug)]
+struct ImageFile {
+    filename: String,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct ImageMetadata {
+    files: Vec<ImageFile>,
+    remote_package_size: u64,
+}
+
+/// A filesystem image in the format of Emscripten's file packager: the
+/// contents of every file one after the other in a data file, and JSON
+/// metadata locating each file in it. webR mounts such images read-only
+/// with `WORKERFS`.
+pub struct FsImage {
+    path: PathBuf,
+    data: BufWriter<File>,
+    files: Vec<ImageFile>,
+    size: u64,
+}
+
+impl FsImage {
+    /// Starts an image written to the data file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> BundlerResult<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            path: path.to_path_buf(),
+            data: BufWriter::new(File::create(path).map_err(Error::bundle(path))?),
+            files: Vec::new(),
+            size: 0,
+        })
+    }
+    /// Adds a file at `name`, relative to the root of the image. Errors are
+    /// left to the caller, which knows where `contents` come from.
+    pub fn add(&mut self, name: &Path, mut contents: impl Read) -> std::io::Result<()> {
+        let start = self.size;
+        self.size += std::io::copy(&mut contents, &mut self.data)?;
+        let filename = name
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        self.files.push(ImageFile {
+            filename: format!("/{}", filename),
+            start,
+            end: self.size,
+        });
+        Ok(())
+    }
+    /// Flushes the data file and writes the metadata to `metadata_path`.
+    pub fn finish(mut self, metadata_path: impl AsRef<Path>) -> BundlerResult<()> {
+        self.data.flush().map_err(Error::bundle(&self.path))?;
+        let metadata = ImageMetadata {
+            files: self.files,
+            remote_package_size: self.size,
+        };
+        let metadata_path = metadata_path.as_ref();
+        std::fs::write(metadata_path, serde_json::to_string(&metadata)?)
+            .map_err(Error::bundle(metadata_path))
+    }
+}
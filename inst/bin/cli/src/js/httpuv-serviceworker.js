@@ -1,4 +1,5 @@
 let wasmClientId;
+const debug = {{ debug }};
 let requests = {};
 
 function promiseHandles() {
@@ -32,12 +33,12 @@ function addCoiHeaders(resp) {
 }
 
 const handleInstall = () => {
-  console.log('Service worker installed');
+  if (debug) console.log('Service worker installed');
   self.skipWaiting();
 };
 
 const handleActivate = (event) => {
-  console.log('Service worker activated');
+  if (debug) console.log('Service worker activated');
   event.waitUntil(self.clients.claim());
 };
 
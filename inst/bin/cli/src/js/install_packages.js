@@ -1,3 +1,39 @@
-export default async function installPackages(webR) {
-  await webR.evalRVoid(`webr::install("shiny", repos="${window.location.href}/repo/")`);
+// Size of every package tarball from the bundle manifest, by package name.
+// Empty when the manifest can't be read, progress is then counted in
+// packages only.
+async function packageSizes() {
+  try {
+    const res = await fetch(new URL({{ manifest_url }}, import.meta.url));
+    const manifest = await res.json();
+    return Object.fromEntries(manifest.packages.map((pkg) => [pkg.name, pkg.size ?? 0]));
+  } catch (e) {
+    return {};
+  }
+}
+
+// The repo URL is relative to the bundle, which is where this module lives.
+// Packages are listed each one after its dependencies and installed one at a
+// time, calling `onProgress` before each of them and once all are done. A
+// package that can't be loaded once installed fails the whole install.
+export default async function installPackages(webR, onProgress = () => {}) {
+  const packages = {{ packages }};
+  const repo = new URL({{ repo_url }}, import.meta.url).href;
+  const sizes = await packageSizes();
+  const totalBytes = packages.reduce((total, name) => total + (sizes[name] ?? 0), 0);
+  let bytes = 0;
+  for (const [index, name] of packages.entries()) {
+    onProgress({ package: name, index, total: packages.length, bytes, totalBytes });
+    await webR.evalRVoid('webr::install(package, repos = repo)', {
+      env: { package: name, repo },
+    });
+    // webr::install() only warns when a package can't be installed
+    const installed = await webR.evalRBoolean('requireNamespace(package, quietly = TRUE)', {
+      env: { package: name },
+    });
+    if (!installed) {
+      throw new Error(`Unable to install the R package ${name} from ${repo}`);
+    }
+    bytes += sizes[name] ?? 0;
+  }
+  onProgress({ package: null, index: packages.length, total: packages.length, bytes, totalBytes });
 }
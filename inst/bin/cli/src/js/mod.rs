@@ -1,69 +1,281 @@
-use crate::errors::BundlerResult;
+use crate::bundle::{
+    BundleOptions, APP_FILES_DIR, APP_FILES_MANIFEST, APP_IMAGE_DATA, APP_IMAGE_METADATA,
+};
+use crate::cli::{AppPackaging, Mode, RuntimeArgs};
+use crate::config::RuntimeConfig;
+use crate::entry::Entry;
+use crate::errors::{BundlerResult, Error};
+use crate::library::{LIBRARY_DATA, LIBRARY_METADATA};
+use crate::manifest::MANIFEST_FILE;
+use crate::template::{render, Variables};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::path::Path;
 
-use crate::renv::{Package, RenvLock};
-
-const SHINY_JS_FILE: &str = include_str!("shiny.js");
-const HTTPUV_SERVICEWORKER_JS_FILE: &str = include_str!("httpuv-serviceworker.js");
-
-fn build_full_install_command(packages: &[Package]) -> String {
-    let packages = packages
-        .iter()
-        .map(|pkg| pkg.get_package().0)
-        .map(|pkg| format!(r#""{}""#, pkg))
-        .collect::<Vec<String>>()
-        .join(", ");
-    [
-        "export async function installPackages(webR) {",
-        &format!(r#"await webR.evalRVoid(`webr::install(c({}), repos="${{window.location.href}}/repo/")`);"#, packages),
-        "}",
-    ]
-    .join("\n")
+const SHINY_JS_TEMPLATE: &str = include_str!("shiny.js");
+const HTTPUV_SERVICEWORKER_JS_TEMPLATE: &str = include_str!("httpuv-serviceworker.js");
+const INSTALL_PACKAGES_JS_TEMPLATE: &str = include_str!("install_packages.js");
+
+const DEFAULT_BASE_PATH: &str = "./";
+const DEFAULT_REPO_URL: &str = "repo/";
+const DEFAULT_MOUNT_PATH: &str = "/home/web_user/app";
+/// Where the package library image is mounted in the webR filesystem.
+const LIBRARY_MOUNT_PATH: &str = "/home/web_user/library";
+
+/// Where the runtime finds the app files, relative to the base path.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AppPayload {
+    /// An archive extracted by R.
+    Archive { archive: String },
+    /// A filesystem image mounted at the mount path.
+    Image { data: String, metadata: String },
+    /// Files in `dir`, listed in `manifest`, written one by one.
+    Files { manifest: String, dir: String },
 }
 
-fn write_shiny_js_file(outdir: impl AsRef<Path>) -> BundlerResult<()> {
-    let outfile = outdir.as_ref().join("shiny.js");
-    std::fs::write(outfile, SHINY_JS_FILE)?;
-    Ok(())
+impl From<&BundleOptions> for AppPayload {
+    fn from(options: &BundleOptions) -> Self {
+        match options.packaging {
+            AppPackaging::Archive => AppPayload::Archive {
+                archive: options.format.file_name().to_string(),
+            },
+            AppPackaging::Image => AppPayload::Image {
+                data: APP_IMAGE_DATA.to_string(),
+                metadata: APP_IMAGE_METADATA.to_string(),
+            },
+            AppPackaging::Files => AppPayload::Files {
+                manifest: APP_FILES_MANIFEST.to_string(),
+                dir: APP_FILES_DIR.to_string(),
+            },
+        }
+    }
 }
 
-fn write_httpuv_serviceworker_js_file(outdir: impl AsRef<Path>) -> BundlerResult<()> {
-    let outfile = outdir.as_ref().join("httpuv-serviceworker.js");
-    std::fs::write(outfile, HTTPUV_SERVICEWORKER_JS_FILE)?;
-    Ok(())
+/// The package library image and where it is mounted.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LibraryImage {
+    pub data: String,
+    pub metadata: String,
+    pub mount_path: String,
 }
 
-fn write_install_packages(outdir: impl AsRef<Path>, renv_lock: &RenvLock) -> BundlerResult<()> {
-    let packages = renv_lock.packages().cloned().collect::<Vec<Package>>();
-    let command = build_full_install_command(&packages);
-    let outfile = outdir.as_ref().join("install_packages.js");
-    std::fs::write(outfile, command)?;
-    Ok(())
+/// Everything the generated JavaScript needs to know about the bundle.
+#[derive(Debug, Clone)]
+pub struct JsContext {
+    /// Packages to install, each one after its dependencies
+    pub packages: Vec<String>,
+    pub base_path: String,
+    pub webr_url: String,
+    pub repo_url: String,
+    /// URL of the bundle manifest, which has the size of every package
+    pub manifest_url: String,
+    pub mount_path: String,
+    pub entry: Entry,
+    pub app_payload: AppPayload,
+    /// Library image mounted instead of installing the packages
+    pub library_image: Option<LibraryImage>,
+    pub display_mode: String,
+    pub shiny_options: BTreeMap<String, serde_json::Value>,
+    /// Log webR's output and progress messages to the browser console
+    pub debug: bool,
+}
+
+fn parse_option_value(raw: &str) -> serde_json::Value {
+    serde_json::from_str(raw).unwrap_or_else(|_| serde_json::Value::String(raw.to_string()))
 }
 
-pub fn write_javascript(outdir: impl AsRef<Path>, renv_lock: &RenvLock) -> BundlerResult<()> {
-    write_shiny_js_file(outdir.as_ref())?;
-    write_httpuv_serviceworker_js_file(outdir.as_ref())?;
-    write_install_packages(outdir.as_ref(), renv_lock)?;
+impl JsContext {
+    /// Builds the context from the command line and the configuration file,
+    /// command line options taking precedence. `webr_url` is the URL of the
+    /// webR runtime picked by the build and is used unless overridden.
+    /// Relative URLs are resolved against the base path. The mode sets the
+    /// defaults of the display mode, Shiny's tracing and logging.
+    ///
+    /// `app_path` is the app's directory inside a multi-app bundle. It is
+    /// appended to the base path and the default repository URL points to
+    /// the repository shared by all apps.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        packages: Vec<String>,
+        webr_url: String,
+        app_path: Option<&str>,
+        entry: Entry,
+        bundle: &BundleOptions,
+        mode: Mode,
+        args: &RuntimeArgs,
+        config: &RuntimeConfig,
+    ) -> BundlerResult<Self> {
+        let pick = |cli: Option<&str>, config: &Option<String>, default: &str| {
+            cli.or(config.as_deref()).unwrap_or(default).to_string()
+        };
+        let (default_display_mode, debug) = match mode {
+            Mode::Dev => ("showcase", true),
+            Mode::Prod => ("normal", false),
+        };
+        let mut shiny_options = BTreeMap::new();
+        shiny_options.insert("shiny.trace".to_string(), serde_json::Value::Bool(debug));
+        for (key, value) in &config.shiny_options {
+            shiny_options.insert(key.clone(), serde_json::to_value(value)?);
+        }
+        for (key, value) in args.shiny_options() {
+            shiny_options.insert(key.clone(), parse_option_value(value));
+        }
+        let base_path = match (args.base_path().or(config.base_path.as_deref()), app_path) {
+            (Some(base_path), Some(app_path)) => {
+                format!("{}/{}", base_path.trim_end_matches('/'), app_path)
+            }
+            (Some(base_path), None) => base_path.to_string(),
+            (None, _) => DEFAULT_BASE_PATH.to_string(),
+        };
+        // Shared files live at the root of a multi-app bundle
+        let bundle_root = match app_path {
+            Some(_) => "../../",
+            None => "",
+        };
+        let default_repo_url = format!("{}{}", bundle_root, DEFAULT_REPO_URL);
+        Ok(Self {
+            packages,
+            base_path,
+            webr_url: pick(args.webr_url(), &config.webr_url, &webr_url),
+            repo_url: pick(args.repo_url(), &config.repo_url, &default_repo_url),
+            manifest_url: format!("{}{}", bundle_root, MANIFEST_FILE),
+            mount_path: pick(args.mount_path(), &config.mount_path, DEFAULT_MOUNT_PATH),
+            entry,
+            app_payload: AppPayload::from(bundle),
+            library_image: None,
+            display_mode: pick(
+                args.display_mode(),
+                &config.display_mode,
+                default_display_mode,
+            ),
+            shiny_options,
+            debug,
+        })
+    }
+    /// Mounts the package library image instead of installing the packages.
+    /// `bundle_root` is the root of the bundle relative to the base path.
+    pub fn with_library_image(mut self, bundle_root: &str) -> Self {
+        self.library_image = Some(LibraryImage {
+            data: format!("{}{}", bundle_root, LIBRARY_DATA),
+            metadata: format!("{}{}", bundle_root, LIBRARY_METADATA),
+            mount_path: LIBRARY_MOUNT_PATH.to_string(),
+        });
+        self
+    }
+    fn variables(&self) -> BundlerResult<Variables> {
+        Variables::new()
+            .js("packages", &self.packages)?
+            .js("base_path", &self.base_path)?
+            .js("webr_url", &self.webr_url)?
+            .js("repo_url", &self.repo_url)?
+            .js("manifest_url", &self.manifest_url)?
+            .js("mount_path", &self.mount_path)?
+            .js("entry", &self.entry)?
+            .js("app_payload", &self.app_payload)?
+            .js("library_image", &self.library_image)?
+            .js("display_mode", &self.display_mode)?
+            .js("shiny_options", &self.shiny_options)?
+            .js("debug", &self.debug)
+    }
+}
+
+fn write_js_file(
+    outdir: impl AsRef<Path>,
+    name: &str,
+    template: &str,
+    variables: &Variables,
+) -> BundlerResult<()> {
+    let outfile = outdir.as_ref().join(name);
+    std::fs::write(&outfile, render(template, variables)?).map_err(Error::bundle(&outfile))
+}
+
+pub fn write_javascript(outdir: impl AsRef<Path>, context: &JsContext) -> BundlerResult<()> {
+    let variables = context.variables()?;
+    write_js_file(outdir.as_ref(), "shiny.js", SHINY_JS_TEMPLATE, &variables)?;
+    write_js_file(
+        outdir.as_ref(),
+        "httpuv-serviceworker.js",
+        HTTPUV_SERVICEWORKER_JS_TEMPLATE,
+        &variables,
+    )?;
+    write_js_file(
+        outdir.as_ref(),
+        "install_packages.js",
+        INSTALL_PACKAGES_JS_TEMPLATE,
+        &variables,
+    )?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use boa_ast::declaration::ExportDeclaration;
+    use boa_ast::ModuleItem;
+    use boa_interner::Interner;
+    use boa_parser::{Parser, Source};
+
+    fn context() -> JsContext {
+        JsContext {
+            packages: vec!["test".to_string(), "test2".to_string()],
+            base_path: "/apps/my-app/".to_string(),
+            webr_url: "https://webr.r-wasm.org/v0.2.2/".to_string(),
+            repo_url: DEFAULT_REPO_URL.to_string(),
+            manifest_url: MANIFEST_FILE.to_string(),
+            mount_path: DEFAULT_MOUNT_PATH.to_string(),
+            entry: Entry::Dir("app's dir".to_string()),
+            app_payload: AppPayload::Image {
+                data: APP_IMAGE_DATA.to_string(),
+                metadata: APP_IMAGE_METADATA.to_string(),
+            },
+            library_image: None,
+            display_mode: "showcase".to_string(),
+            shiny_options: BTreeMap::from([("shiny.trace".to_string(), true.into())]),
+            debug: true,
+        }
+    }
+
+    fn render_template(template: &str) -> String {
+        render(template, &context().variables().unwrap()).unwrap()
+    }
+
+    fn parse_script(source: &str) {
+        Parser::new(Source::from_bytes(source))
+            .parse_script(&mut Interner::default())
+            .expect("generated script should parse");
+    }
+
+    #[test]
+    fn test_install_packages_is_a_default_export() {
+        let rendered = render_template(INSTALL_PACKAGES_JS_TEMPLATE);
+        assert!(rendered.contains(r#"const packages = ["test","test2"];"#));
+        let module = Parser::new(Source::from_bytes(&rendered))
+            .parse_module(&mut Interner::default())
+            .expect("install_packages.js should parse as a module");
+        let exports_default = module.items().items().iter().any(|item| {
+            matches!(
+                item,
+                ModuleItem::ExportDeclaration(ExportDeclaration::DefaultAsyncFunction(_))
+            )
+        });
+        assert!(exports_default);
+    }
+
+    #[test]
+    fn test_shiny_js_parses() {
+        let rendered = render_template(SHINY_JS_TEMPLATE);
+        assert!(rendered.contains(r#"new URL("/apps/my-app/", document.baseURI)"#));
+        assert!(rendered.contains(r#"entry: {"type":"dir","value":"app's dir"}"#));
+        assert!(rendered.contains("{ default: installPackages }"));
+        assert!(rendered.contains(
+            r#"const appPayload = {"type":"image","data":"app.data","metadata":"app.metadata.json"};"#
+        ));
+        parse_script(&rendered);
+    }
 
     #[test]
-    fn test_build_full_install_command() {
-        let packages = vec![
-            Package::new("test", "0.0.1", ""),
-            Package::new("test2", "0.0.1", ""),
-        ];
-        let command = build_full_install_command(&packages);
-        assert_eq!(
-            command,
-            r#"export default async function installPackages(webR) {
-await webR.evalRVoid(`webr::install(c("test", "test2"), repos="${window.location.href}/repo/")`);
-}"#
-        );
+    fn test_serviceworker_parses() {
+        parse_script(&render_template(HTTPUV_SERVICEWORKER_JS_TEMPLATE));
     }
 }
@@ -1,8 +1,72 @@
 // Each client session has a unique ID
 let clientId;
 
+// Progress messages and R's output are only logged in dev mode
+const debug = {{ debug }};
+function debugLog(...args) {
+  if (debug) {
+    console.log(...args);
+  }
+}
+
+// Loading progress is shown in the #progress bar and #progress-label of the
+// page, if it has them, and dispatched as `webr-bundle:progress` events for
+// custom templates. `fraction` is null while the progress is unknown.
+function reportProgress(message, fraction = null, detail = {}) {
+  debugLog(message);
+  document.dispatchEvent(new CustomEvent('webr-bundle:progress', {
+    detail: { message, fraction, ...detail },
+  }));
+  const label = document.getElementById('progress-label');
+  if (label) {
+    label.textContent = message;
+  }
+  const bar = document.getElementById('progress');
+  if (bar) {
+    if (fraction === null) {
+      bar.removeAttribute('value');
+    } else {
+      bar.value = fraction;
+    }
+  }
+}
+
+// Failures replace the loading message with the #error panel of the page,
+// if it has one, and are dispatched as `webr-bundle:error` events
+function reportError(error) {
+  console.error(error);
+  document.dispatchEvent(new CustomEvent('webr-bundle:error', { detail: { error } }));
+  const panel = document.getElementById('error');
+  if (!panel) {
+    return;
+  }
+  const loading = document.getElementById('loading');
+  if (loading) {
+    loading.style.display = 'none';
+  }
+  const message = document.getElementById('error-message');
+  if (message) {
+    message.textContent = String(error?.message ?? error);
+  }
+  panel.hidden = false;
+}
+
+function formatBytes(bytes) {
+  const units = ['B', 'KB', 'MB', 'GB'];
+  let unit = 0;
+  while (bytes >= 1000 && unit < units.length - 1) {
+    bytes /= 1000;
+    unit++;
+  }
+  return `${bytes.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+// Every bundle file is resolved against this URL, so the bundle can be
+// hosted under any path
+const bundleBaseUrl = new URL({{ base_path }}, document.baseURI).href;
+
 // Register service worker
-navigator.serviceWorker.register('./httpuv-serviceworker.js').then(async (registration)=>{
+navigator.serviceWorker.register(new URL('httpuv-serviceworker.js', bundleBaseUrl)).then(async (registration)=>{
   await navigator.serviceWorker.ready;
 
   // If the service worker is ready, but not in control, reload the main page
@@ -20,11 +84,15 @@ navigator.serviceWorker.register('./httpuv-serviceworker.js').then(async (regist
     });
     registration.active.postMessage({type: "register-client"});
   });
-  console.log('I am client: ', clientId);
-  console.log("serviceworker proxy is ready");
+  debugLog('I am client: ', clientId);
+  debugLog("serviceworker proxy is ready");
 });
 
-import('https://webr.r-wasm.org/latest/webr.mjs').then(async ({ WebR }) => {
+// The webR worker resolves its files against this URL, so it must be absolute
+const webRBaseUrl = new URL({{ webr_url }}, bundleBaseUrl).href;
+
+reportProgress('Loading webR...');
+import(webRBaseUrl + 'webr.mjs').then(async ({ WebR }) => {
   let webSocketHandleCounter = 0;
   let webSocketRefs = {};
 
@@ -73,9 +141,27 @@ import('https://webr.r-wasm.org/latest/webr.mjs').then(async ({ WebR }) => {
   }
 
   // Initialise webR with a local package repo
-  const webR = new WebR();
+  const webR = new WebR({ baseUrl: webRBaseUrl });
   await webR.init();
-  console.log("webR init OK");
+  debugLog("webR init OK");
+  reportProgress('Loading the app...');
+
+  // Write R output to the page console, if the page has one. Otherwise
+  // errors go to the browser console and the rest only in dev mode.
+  function writeOutput(text, isError) {
+    const out = document.getElementById('out');
+    if (!out) {
+      if (isError) {
+        console.warn(text);
+      } else {
+        debugLog(text);
+      }
+      return;
+    }
+    out.append(text + '\n');
+    const console_ = document.getElementById('console');
+    console_.scrollTop = console_.scrollHeight;
+  }
 
   // Read webR channel for events
   (async () => {
@@ -83,14 +169,10 @@ import('https://webr.r-wasm.org/latest/webr.mjs').then(async ({ WebR }) => {
       const output = await webR.read();
       switch (output.type) {
       case 'stdout':
-        document.getElementById('out').append(output.data + '\n');
-        document.getElementById('console').scrollTop = 
-        document.getElementById('console').scrollHeight;
+        writeOutput(output.data, false);
         break;
       case 'stderr':
-        document.getElementById('out').append(output.data + '\n');
-        document.getElementById('console').scrollTop = 
-        document.getElementById('console').scrollHeight;
+        writeOutput(output.data, true);
         break;
       case '_webR_httpuv_TcpResponse':
         const registration = await navigator.serviceWorker.getRegistration();
@@ -115,20 +197,147 @@ import('https://webr.r-wasm.org/latest/webr.mjs').then(async ({ WebR }) => {
     await webR.FS.writeFile(path, new Uint8Array(data));
   }
 
-  // Setup shiny app on webR VFS
-  await webR.FS.mkdir('/home/web_user/app');
-  await fetchToWebR('app.tgz', '/home/web_user/app.tgz');
+  // Runs `task` on every item, at most `limit` of them at a time
+  async function forEachLimit(items, limit, task) {
+    let next = 0;
+    const workers = Array.from({ length: Math.min(limit, items.length) }, async () => {
+      while (next < items.length) {
+        await task(items[next++]);
+      }
+    });
+    await Promise.all(workers);
+  }
+
+  // Create a directory and its missing parents on webR VFS
+  async function mkdirs(path) {
+    let dir = '';
+    for (const part of path.split('/').filter(Boolean)) {
+      dir += '/' + part;
+      try {
+        await webR.FS.mkdir(dir);
+      } catch (e) {
+        // The directory already exists
+      }
+    }
+  }
+
+  // Mount a filesystem image read-only on webR VFS
+  async function mountImage(metadataUrl, dataUrl, path) {
+    const [metadata, blob] = await Promise.all([
+      fetch(metadataUrl).then((res) => res.json()),
+      fetch(dataUrl).then((res) => res.blob()),
+    ]);
+    await mkdirs(path);
+    await webR.FS.mount('WORKERFS', { packages: [{ metadata, blob }] }, path);
+  }
+
+  // Setup shiny app on webR VFS. Archives are extracted by R below, images
+  // and single files are available at the mount path right away
+  const appPayload = {{ app_payload }};
+  const mountPath = {{ mount_path }};
+  let archive = null;
+  switch (appPayload.type) {
+    case 'image':
+      await mountImage(
+        new URL(appPayload.metadata, bundleBaseUrl),
+        new URL(appPayload.data, bundleBaseUrl),
+        mountPath,
+      );
+      break;
+    case 'files': {
+      const manifest = await fetch(new URL(appPayload.manifest, bundleBaseUrl)).then((res) => res.json());
+      await mkdirs(mountPath);
+      for (const dir of manifest.directories) {
+        await mkdirs(`${mountPath}/${dir}`);
+      }
+      // As many requests as browsers make to one host over HTTP/1.1
+      await forEachLimit(manifest.files, 6, (file) => {
+        const url = new URL(`${appPayload.dir}/${file.split('/').map(encodeURIComponent).join('/')}`, bundleBaseUrl);
+        return fetchToWebR(url, `${mountPath}/${file}`);
+      });
+      break;
+    }
+    default:
+      archive = `/tmp/${appPayload.archive}`;
+      await fetchToWebR(new URL(appPayload.archive, bundleBaseUrl), archive);
+  }
+
+  // Packages are either mounted from the library built with the bundle or
+  // installed from the package repository
+  const libraryImage = {{ library_image }};
+  if (libraryImage) {
+    reportProgress('Loading packages...');
+    await mountImage(
+      new URL(libraryImage.metadata, bundleBaseUrl),
+      new URL(libraryImage.data, bundleBaseUrl),
+      libraryImage.mount_path,
+    );
+    // The image is read-only, so the writable user library stays first for
+    // packages installed at runtime
+    await webR.evalRVoid('.libPaths(c(.libPaths()[1], library, .libPaths()[-1]))', {
+      env: { library: libraryImage.mount_path },
+    });
+  } else {
+    const { default: installPackages } = await import(new URL('install_packages.js', bundleBaseUrl));
+    await installPackages(webR, ({ package: name, index, total, bytes, totalBytes }) => {
+      const fraction = totalBytes > 0 ? bytes / totalBytes : index / Math.max(total, 1);
+      let message = name ? `Installing ${name} (${index + 1}/${total})` : 'Packages installed';
+      if (totalBytes > 0) {
+        message += `, ${formatBytes(bytes)} of ${formatBytes(totalBytes)}`;
+      }
+      reportProgress(message, fraction, { package: name, index, total, bytes, totalBytes });
+    });
+  }
+  reportProgress('Starting the app...');
 
-  await import('/install_packages.js').then(async ({ installPackages }) => {
-    await installPackages(webR);
+  // Values are passed as R objects instead of being pasted into R code
+  await webR.evalRVoid(`
+    if (!is.null(archive)) {
+      dir.create(mount_path, recursive = TRUE, showWarnings = FALSE)
+      # The compression of the archive is detected when reading it
+      utils::untar(archive, exdir = mount_path, tar = "internal", extras = "--no-same-permissions")
+    }
+    do.call(options, shiny_options)
+    app <- switch(entry$type,
+      call = {
+        # Package-style app: source its R files, then call the app function.
+        # The package isn't installed, so as pkgload does, system.file() looks
+        # up its files in inst/ for golem's app_sys() and the like
+        description <- file.path(mount_path, "DESCRIPTION")
+        if (file.exists(description)) {
+          package_name <- read.dcf(description, fields = "Package")[1, 1]
+          assign("system.file", function(..., package = "base", lib.loc = NULL, mustWork = FALSE) {
+            if (!identical(package, package_name)) {
+              return(base::system.file(..., package = package, lib.loc = lib.loc, mustWork = mustWork))
+            }
+            path <- file.path(mount_path, "inst", ...)
+            if (file.exists(path)) return(path)
+            if (mustWork) stop("no file found")
+            ""
+          }, envir = globalenv())
+        }
+        r_files <- list.files(file.path(mount_path, "R"), pattern = "[.][Rr]$", full.names = TRUE)
+        for (r_file in sort(r_files)) sys.source(r_file, envir = globalenv())
+        eval(parse(text = entry$value), envir = globalenv())
+      },
+      file.path(mount_path, entry$value)
+    )
+    setwd(mount_path)
+    options(webr_bundle.app = app, webr_bundle.display_mode = display_mode)
+  `, {
+    env: {
+      archive,
+      mount_path: mountPath,
+      entry: {{ entry }},
+      display_mode: {{ display_mode }},
+      shiny_options: {{ shiny_options }},
+    },
   });
 
-  // Install and run shiny
+  // Run shiny
   webR.writeConsole(`
-    utils::untar("/home/web_user/app.tgz", exdir = "/home/web_user/app", tar = "internal", extras = "--no-same-permissions")
     library(shiny)
-    options(shiny.trace = TRUE)
-    runApp('app', display.mode = 'showcase')
+    runApp(getOption("webr_bundle.app"), display.mode = getOption("webr_bundle.display_mode"))
   `);
 
   // Setup listener for service worker messages
@@ -153,21 +362,19 @@ import('https://webr.r-wasm.org/latest/webr.mjs').then(async ({ WebR }) => {
     }
   });
 
-  // Load the WASM httpuv hosted page in an iframe
+  // Load the WASM httpuv hosted page in an iframe, the page template styles
+  // it through the #app selector
   let iframe = document.createElement('iframe');
   iframe.id = 'app';
-  iframe.src = `./__wasm__/${clientId}/`;
+  iframe.src = new URL(`__wasm__/${clientId}/`, bundleBaseUrl);
   iframe.frameBorder = '0';
-  iframe.style.position = 'fixed';
-  iframe.style.top = 0;
-  iframe.style.left = 0;
-  iframe.style.right = 0;
-  iframe.style.width = '100%';
-  iframe.style.height = '80%';
   document.body.appendChild(iframe);
   // Install the websocket proxy for chatting to httpuv
   iframe.contentWindow.WebSocket = WebSocketProxy;
 
   // Hide the loading div
-  document.getElementById('loading').style.display = "none";
-});
+  const loading = document.getElementById('loading');
+  if (loading) {
+    loading.style.display = "none";
+  }
+}).catch(reportError);
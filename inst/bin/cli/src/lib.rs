@@ -1,10 +1,26 @@
+pub mod build;
 pub mod bundle;
 pub mod cli;
+pub mod config;
 pub mod download;
+pub mod entry;
 pub mod errors;
+pub mod events;
 pub mod html;
+pub mod http;
+pub mod image;
 pub mod js;
+pub mod library;
 pub mod logs;
+pub mod manifest;
+pub mod mirror;
+pub mod progress;
 pub mod renv;
 pub mod repo;
+pub mod report;
+pub mod repository;
 pub mod serve;
+pub mod size;
+pub mod template;
+pub mod watch;
+pub mod webr;
@@ -0,0 +1,93 @@
+use crate::download::contrib_dir;
+use crate::errors::{BundlerResult, Error};
+use crate::image::FsImage;
+use crate::renv::RenvLock;
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use std::fs::File;
+use std::path::{Component, Path};
+
+/// Data file and metadata of the package library image, at the root of the
+/// bundle.
+pub const LIBRARY_DATA: &str = "library.data";
+pub const LIBRARY_METADATA: &str = "library.metadata.json";
+
+/// Extracts the downloaded tarball of every package into one filesystem
+/// image, laid out like an R library: one directory per package. Packages
+/// whose tarball could not be downloaded are left out.
+pub fn write_library_image(
+    outdir: impl AsRef<Path>,
+    renv_lock: &RenvLock,
+    r_version: &str,
+) -> BundlerResult<()> {
+    info!("Building package library image...");
+    let contrib = contrib_dir(outdir.as_ref(), r_version);
+    let mut image = FsImage::create(outdir.as_ref().join(LIBRARY_DATA))?;
+    for package in renv_lock.packages() {
+        let (name, version) = package.get_package();
+        let tarball = contrib.join(format!("{}_{}.tgz", name, version));
+        if !tarball.is_file() {
+            warn!(
+                "{} is missing from the library image",
+                package.to_string().yellow()
+            );
+            continue;
+        }
+        let file = File::open(&tarball).map_err(Error::bundle(&tarball))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        for entry in archive.entries().map_err(Error::bundle(&tarball))? {
+            let entry = entry.map_err(Error::bundle(&tarball))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path().map_err(Error::bundle(&tarball))?.into_owned();
+            // Entries escaping the library would be mounted elsewhere
+            if !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                continue;
+            }
+            image.add(&path, entry).map_err(Error::bundle(&tarball))?;
+        }
+    }
+    image.finish(outdir.as_ref().join(LIBRARY_METADATA))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renv::Package;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_library_image() {
+        let outdir = tempfile::tempdir().unwrap();
+        let contrib = contrib_dir(outdir.path(), "4.3");
+        std::fs::create_dir_all(&contrib).unwrap();
+        let tarball = File::create(contrib.join("shiny_1.7.5.tgz")).unwrap();
+        let mut tar = tar::Builder::new(GzEncoder::new(tarball, Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(14);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "shiny/DESCRIPTION", &b"Package: shiny"[..])
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+        let mut renv_lock = RenvLock::default();
+        for (name, version) in [("shiny", "1.7.5"), ("missing", "1.0")] {
+            let package = Package::new(name, version, "");
+            renv_lock.packages_mut().insert(name.to_string(), package);
+        }
+
+        write_library_image(outdir.path(), &renv_lock, "4.3").unwrap();
+        let metadata = std::fs::read_to_string(outdir.path().join(LIBRARY_METADATA)).unwrap();
+        assert_eq!(
+            metadata,
+            r#"{"files":[{"filename":"/shiny/DESCRIPTION","start":0,"end":14}],"remote_package_size":14}"#
+        );
+        let data = std::fs::read(outdir.path().join(LIBRARY_DATA)).unwrap();
+        assert_eq!(data, b"Package: shiny");
+    }
+}
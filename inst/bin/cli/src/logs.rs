@@ -1,8 +1,169 @@
-use env_logger::{Builder, Env};
-use log::LevelFilter;
+use crate::cli::{ColorChoice, LogArgs};
+use crate::errors::{BundlerResult, Error};
+use crate::events::{emit, json_output, Event};
+use crate::progress;
+use colored::Colorize;
+use env_logger::filter::{Builder, Filter};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use regex::Regex;
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
 
-pub fn init() {
-    Builder::from_env(Env::new().filter_or("WEBR_LOG", "info"))
-        .filter_module("actix_server", LevelFilter::Off)
-        .init()
+/// Prints records for people on stderr and, with `--log-file`, writes them
+/// uncolored and with their timings to a file.
+struct Logger {
+    stderr: Filter,
+    file: Option<(Filter, Mutex<File>)>,
+    start: Instant,
+}
+
+/// Matches the escape sequences `colored` adds to messages.
+fn ansi_escapes() -> &'static Regex {
+    static ANSI_ESCAPES: OnceLock<Regex> = OnceLock::new();
+    ANSI_ESCAPES.get_or_init(|| Regex::new(r"\x1b\[[0-9;]*m").expect("Invalid regex"))
+}
+
+fn strip_colors(message: &str) -> String {
+    ansi_escapes().replace_all(message, "").to_string()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|(filter, _)| filter.enabled(metadata))
+    }
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        if self.stderr.matches(record) {
+            progress::suspend(|| match record.level() {
+                Level::Error => eprintln!("{}: {}", "ERROR".red().bold(), message),
+                Level::Warn => eprintln!("{}: {}", "WARNING".yellow().bold(), message),
+                Level::Info => eprintln!("{}", message),
+                level => eprintln!(
+                    "{} {}",
+                    format!("[{} {}]", level, record.target()).dimmed(),
+                    message
+                ),
+            });
+        }
+        if record.level() == Level::Warn && json_output() {
+            emit(Event::Warning {
+                message: strip_colors(&message),
+            });
+        }
+        if let Some((filter, file)) = &self.file {
+            if filter.matches(record) {
+                let mut file = file.lock().expect("Log file lock poisoned");
+                let _ = writeln!(
+                    file,
+                    "[{:>8.3}s {:<5} {}] {}",
+                    self.start.elapsed().as_secs_f64(),
+                    record.level(),
+                    record.target(),
+                    strip_colors(&message)
+                );
+            }
+        }
+    }
+    fn flush(&self) {
+        if let Some((_, file)) = &self.file {
+            let _ = file.lock().expect("Log file lock poisoned").flush();
+        }
+    }
+}
+
+/// Builds a filter showing our messages from `level`, unless `WEBR_LOG`
+/// sets the filters. Dependencies only show warnings, and debug messages
+/// at the trace level.
+fn filter(level: LevelFilter) -> Filter {
+    let mut builder = Builder::new();
+    match std::env::var("WEBR_LOG") {
+        Ok(spec) => builder.parse(&spec),
+        Err(_) => {
+            let dependencies = match level {
+                LevelFilter::Trace => LevelFilter::Debug,
+                level => level.min(LevelFilter::Warn),
+            };
+            // Matches the `webr_bundle` modules and the `webr::server` target
+            builder
+                .filter_level(dependencies)
+                .filter_module("webr", level)
+        }
+    };
+    builder.filter_module("actix_server", LevelFilter::Off);
+    builder.build()
+}
+
+/// Colors are on with `--color always`, off with `--color never`, and
+/// otherwise only when stderr is a terminal and `NO_COLOR` is not set.
+fn use_colors(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").unwrap_or_default().is_empty()
+                && std::io::stderr().is_terminal()
+        }
+    }
+}
+
+/// Sets up logging from the verbosity flags: info by default, each `-v`
+/// adds debug then trace messages, each `-q` drops info then warnings.
+/// `WEBR_LOG` takes precedence with `env_logger` style filters. The log
+/// file gets at least debug messages. Downloads show progress bars when
+/// progress messages are printed to a terminal.
+pub fn init(args: &LogArgs) -> BundlerResult<()> {
+    colored::control::set_override(use_colors(args.color()));
+    let level = match args.verbosity() {
+        i8::MIN..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        2..=i8::MAX => LevelFilter::Trace,
+    };
+    let file = match args.log_file() {
+        Some(path) => Some((
+            filter(level.max(LevelFilter::Debug)),
+            Mutex::new(
+                File::create(path)
+                    .map_err(|err| Error::LogFile(path.to_path_buf(), err.to_string()))?,
+            ),
+        )),
+        None => None,
+    };
+    let stderr = filter(level);
+    // Progress bars would be noise in quiet runs and in log files of CI jobs
+    progress::set_enabled(stderr.filter() >= LevelFilter::Info && std::io::stderr().is_terminal());
+    let logger = Logger {
+        stderr,
+        file,
+        start: Instant::now(),
+    };
+    let max_level = logger
+        .file
+        .as_ref()
+        .map_or(logger.stderr.filter(), |(filter, _)| {
+            filter.filter().max(logger.stderr.filter())
+        });
+    // Warnings are reported as events even when they are not printed
+    log::set_max_level(max_level.max(LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(logger)).expect("Logger already initialized");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_colors() {
+        colored::control::set_override(true);
+        let message = format!("Downloaded {} in {}", "shiny".green(), "1s".cyan().italic());
+        assert_eq!(strip_colors(&message), "Downloaded shiny in 1s");
+    }
 }
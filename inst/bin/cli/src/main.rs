@@ -1,44 +1,55 @@
+use colored::Colorize;
+use log::{error, info};
 use webr_bundle::{
-    bundle::{build_bundle, create_dist_dir},
-    cli::Args,
-    cli::Command,
-    download::download_packages_rds,
+    build::build,
+    cli::{Args, Command, OutputFormat},
     errors::BundlerResult,
-    html::write_index_html_file,
-    js::write_javascript,
+    events::{self, emit, Event},
     logs,
-    renv::RenvLock,
+    mirror::mirror,
+    watch::watch,
 };
 
 #[tokio::main]
 async fn main() {
-    logs::init();
     let args = Args::init();
+    if let Err(err) = logs::init(args.log()) {
+        eprintln!("Unable to open the log file: {}", err);
+        std::process::exit(err.exit_code())
+    }
     if let Err(err) = logic(args).await {
-        eprintln!("{}", err);
-        std::process::exit(1)
+        emit(Event::Error {
+            message: err.to_string(),
+            hint: err.hint().map(str::to_string),
+            exit_code: err.exit_code(),
+        });
+        match err.hint() {
+            Some(hint) => error!("{}\n  {} {}", err, "hint:".cyan().bold(), hint),
+            None => error!("{}", err),
+        }
+        log::logger().flush();
+        std::process::exit(err.exit_code())
     }
 }
 
 async fn logic(args: Args) -> BundlerResult<()> {
     match args.command() {
         Command::Build(build_args) => {
-            let appdir = build_args.appdir();
-            let outdir = build_args.outdir();
-            create_dist_dir(outdir)?;
-            build_bundle(appdir, outdir)?;
-            let mut renv_lock = RenvLock::read_from_file(appdir)?;
-            renv_lock.download(outdir, build_args.parallel()).await?;
-            download_packages_rds(outdir).await?;
-            write_javascript(outdir, &renv_lock)?;
-            write_index_html_file(outdir)?;
+            events::set_json_output(build_args.output() == OutputFormat::Json);
+            build(build_args).await?
         }
         Command::Serve(serve_args) => {
             let port = serve_args.port();
             let outdir = serve_args.outdir().into();
-            eprintln!("Serving on http://localhost:{port}");
+            if serve_args.watch() {
+                let build_args = serve_args.build_args();
+                build(&build_args).await?;
+                tokio::spawn(watch(build_args));
+            }
+            info!("Serving on http://localhost:{port}");
             webr_bundle::serve::server(outdir, port).await?
         }
+        Command::Mirror(mirror_args) => mirror(mirror_args).await?,
     }
     Ok(())
 }
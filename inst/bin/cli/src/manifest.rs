@@ -0,0 +1,92 @@
+use crate::cli::Mode;
+use crate::download::contrib_dir;
+use crate::entry::Entry;
+use crate::errors::{BundlerResult, Error};
+use crate::renv::RenvLock;
+use serde::Serialize;
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "bundle.json";
+
+#[derive(Serialize, Debug)]
+pub struct ManifestPackage {
+    name: String,
+    version: String,
+    /// Size of the package tarball, missing when it was not downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ManifestApp {
+    name: String,
+    path: String,
+    entry: Entry,
+    packages: Vec<String>,
+}
+
+/// Describes what went into a bundle, written next to `index.html` so a
+/// deployment can be traced back to the runtime and packages it uses.
+#[derive(Serialize, Debug)]
+pub struct BundleManifest {
+    bundler_version: &'static str,
+    webr_version: String,
+    webr_url: String,
+    r_version: String,
+    mode: String,
+    apps: Vec<ManifestApp>,
+    packages: Vec<ManifestPackage>,
+}
+
+impl BundleManifest {
+    /// Describes the bundle in `outdir`, once its packages are downloaded.
+    pub fn new(
+        outdir: impl AsRef<Path>,
+        webr_version: &str,
+        webr_url: &str,
+        r_version: &str,
+        mode: Mode,
+        renv_lock: &RenvLock,
+    ) -> Self {
+        let contrib = contrib_dir(outdir, r_version);
+        let packages = renv_lock
+            .packages()
+            .map(|package| {
+                let (name, version) = package.get_package();
+                let tarball = contrib.join(format!("{}_{}.tgz", name, version));
+                ManifestPackage {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    size: std::fs::metadata(tarball)
+                        .ok()
+                        .map(|metadata| metadata.len()),
+                }
+            })
+            .collect();
+        Self {
+            bundler_version: env!("CARGO_PKG_VERSION"),
+            webr_version: webr_version.to_string(),
+            webr_url: webr_url.to_string(),
+            r_version: r_version.to_string(),
+            mode: mode.to_string(),
+            apps: Vec::new(),
+            packages,
+        }
+    }
+    /// Records an app of the bundle. `path` is the directory of its page,
+    /// relative to the root of the bundle, and `packages` are in install
+    /// order.
+    pub fn add_app(&mut self, name: &str, path: &str, entry: &Entry, packages: &[String]) {
+        self.apps.push(ManifestApp {
+            name: name.to_string(),
+            path: path.to_string(),
+            entry: entry.clone(),
+            packages: packages.to_vec(),
+        });
+    }
+    pub fn write(&self, outdir: impl AsRef<Path>) -> BundlerResult<()> {
+        let outfile = outdir.as_ref().join(MANIFEST_FILE);
+        std::fs::write(&outfile, serde_json::to_string_pretty(self)?)
+            .map_err(Error::bundle(&outfile))
+    }
+}
@@ -0,0 +1,317 @@
+use crate::cli::MirrorArgs;
+use crate::config::HttpConfig;
+use crate::download::existing_tarball;
+use crate::errors::{BundlerResult, Error};
+use crate::http::HttpClient;
+use crate::renv::{Package, RenvLock};
+use crate::repo::select_available_packages;
+use crate::repository::{
+    contrib_path, open_repository, PackageRepository, PACKAGES_GZ, PACKAGES_RDS,
+};
+use colored::Colorize;
+use flate2::write::GzEncoder;
+use log::info;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Uncompressed index, the last one R falls back to.
+const PACKAGES: &str = "PACKAGES";
+
+/// The packages given to `--packages`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selection {
+    All,
+    Lockfile(PathBuf),
+    Names(Vec<String>),
+}
+
+impl Selection {
+    /// `all`, then a path to a lock file or to a directory holding one, and
+    /// otherwise a comma-separated list of package names.
+    fn parse(spec: &str) -> Self {
+        let path = Path::new(spec);
+        if spec == "all" {
+            Selection::All
+        } else if path.is_dir() {
+            Selection::Lockfile(path.join("renv.lock"))
+        } else if path.is_file() || spec.ends_with(".lock") {
+            Selection::Lockfile(path.to_path_buf())
+        } else {
+            Selection::Names(
+                spec.split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )
+        }
+    }
+    /// The packages to resolve, with their versions replaced by the ones of
+    /// the repository when resolving.
+    async fn packages(&self, repository: &PackageRepository) -> BundlerResult<RenvLock> {
+        let mut renv_lock = RenvLock::default();
+        match self {
+            Selection::All => renv_lock
+                .packages_mut()
+                .extend(repository.packages().await?.clone()),
+            Selection::Lockfile(path) => renv_lock = RenvLock::read(path)?,
+            Selection::Names(names) => {
+                for name in names {
+                    renv_lock
+                        .packages_mut()
+                        .insert(name.clone(), Package::new(name, "", ""));
+                }
+            }
+        }
+        Ok(renv_lock)
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> BundlerResult<()> {
+    std::fs::write(path, contents).map_err(Error::bundle(path))
+}
+
+/// Names of the packages of the repository whose tarball is in `dir` and
+/// matches the checksum of the index, mirrored by this run or earlier ones.
+async fn mirrored_packages(
+    repository: &PackageRepository,
+    dir: &Path,
+) -> BundlerResult<BTreeSet<String>> {
+    let mut mirrored = BTreeSet::new();
+    for (name, package) in repository.packages().await? {
+        let version = package.get_package().1;
+        let path = dir.join(format!("{}_{}.tgz", name, version));
+        if existing_tarball(&path, package.checksum()).await.is_some() {
+            mirrored.insert(name.clone());
+        }
+    }
+    Ok(mirrored)
+}
+
+/// Writes the indexes of the mirror into `dir`. A complete mirror gets the
+/// indexes of the repository, otherwise they list every package whose
+/// tarball is in `dir`, so that partial mirrors add up, and `PACKAGES.rds`,
+/// which can't be filtered, is left out.
+async fn write_indexes(
+    repository: &PackageRepository,
+    dir: &Path,
+    complete: bool,
+) -> BundlerResult<()> {
+    let index = repository.packages_index().await?;
+    let rds = dir.join(PACKAGES_RDS);
+    if complete {
+        write_file(&dir.join(PACKAGES), index.as_bytes())?;
+        write_file(
+            &dir.join(PACKAGES_GZ),
+            &repository.index_file(PACKAGES_GZ).await?,
+        )?;
+        return match repository.index_file(PACKAGES_RDS).await {
+            Ok(index) => write_file(&rds, &index),
+            Err(Error::Download {
+                status: Some(404), ..
+            }) => Ok(()),
+            Err(err) => Err(err),
+        };
+    }
+    let index = select_available_packages(&index, &mirrored_packages(repository, dir).await?);
+    write_file(&dir.join(PACKAGES), index.as_bytes())?;
+    let gz_path = dir.join(PACKAGES_GZ);
+    let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(index.as_bytes())
+        .map_err(Error::bundle(&gz_path))?;
+    write_file(&gz_path, &gz.finish().map_err(Error::bundle(&gz_path))?)?;
+    if rds.exists() {
+        std::fs::remove_file(&rds).map_err(Error::bundle(&rds))?;
+    }
+    Ok(())
+}
+
+/// Snapshots packages of a repository, with everything they depend on,
+/// into a directory laid out like a repository that builds can use with
+/// `--repo`. Tarballs already in the directory are kept when they match
+/// the checksums of the index, so an interrupted mirror resumes where it
+/// stopped.
+pub async fn mirror(args: &MirrorArgs) -> BundlerResult<()> {
+    let r_version = args.r_version();
+    let client = HttpClient::new(args.http(), &HttpConfig::default(), ".")?;
+    let repository =
+        PackageRepository::new(open_repository(args.repo(), client, r_version)?, r_version);
+    let selection = Selection::parse(args.packages());
+    let mut renv_lock = selection.packages(&repository).await?;
+    renv_lock.resolve(&repository).await?;
+    let dir = args.out().join(contrib_path(r_version));
+    let failed_packages = renv_lock
+        .download_into(&repository, &dir, args.parallel())
+        .await?;
+    let failed = failed_packages
+        .iter()
+        .map(|package| package.get_package().0.to_string())
+        .collect::<BTreeSet<_>>();
+    let mirrored = renv_lock
+        .package_names()
+        .into_iter()
+        .filter(|name| !failed.contains(name))
+        .collect::<BTreeSet<_>>();
+    let complete = selection == Selection::All && failed.is_empty();
+    write_indexes(&repository, &dir, complete).await?;
+    info!(
+        "Mirrored {} packages into {}",
+        mirrored.len().to_string().green(),
+        args.out().display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::Checksum;
+    use clap::Parser;
+    use md5::Digest;
+
+    const SOURCE: &str = "Package: shiny\nVersion: 1.7.5\nImports: httpuv\n\n\
+                          Package: httpuv\nVersion: 1.6.11\n\n\
+                          Package: leaflet\nVersion: 2.2.0\n";
+
+    /// Writes a repository whose index has the checksums of the tarballs,
+    /// except for `broken` which gets a wrong one.
+    fn write_source(root: &Path, broken: Option<&str>) {
+        let dir = root.join(contrib_path("4.3"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut index = String::new();
+        for block in SOURCE.split("\n\n") {
+            let packages = crate::repo::parse_available_packages(block).unwrap();
+            let package = packages.values().next().unwrap();
+            let (name, version) = package.get_package();
+            let tarball = format!("{} tarball", name);
+            std::fs::write(dir.join(format!("{}_{}.tgz", name, version)), &tarball).unwrap();
+            let digest = match broken == Some(name) {
+                true => "0".repeat(32),
+                false => format!("{:x}", md5::Md5::digest(tarball.as_bytes())),
+            };
+            index.push_str(&format!("{}\nMD5sum: {}\n\n", block.trim(), digest));
+        }
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(index.as_bytes()).unwrap();
+        std::fs::write(dir.join(PACKAGES_GZ), gz.finish().unwrap()).unwrap();
+    }
+
+    fn mirror_args(source: &Path, out: &Path, packages: &str) -> MirrorArgs {
+        MirrorArgs::parse_from([
+            "mirror".as_ref(),
+            "--repo".as_ref(),
+            source.as_os_str(),
+            "--out".as_ref(),
+            out.as_os_str(),
+            "--packages".as_ref(),
+            packages.as_ref(),
+        ])
+    }
+
+    #[test]
+    fn test_selection() {
+        assert_eq!(Selection::parse("all"), Selection::All);
+        assert_eq!(
+            Selection::parse("shiny, bslib"),
+            Selection::Names(vec!["shiny".to_string(), "bslib".to_string()])
+        );
+        assert_eq!(
+            Selection::parse("app/renv.lock"),
+            Selection::Lockfile(PathBuf::from("app/renv.lock"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let source = tempfile::tempdir().unwrap();
+        write_source(source.path(), None);
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().join(contrib_path("4.3"));
+        mirror(&mirror_args(source.path(), out.path(), "shiny"))
+            .await
+            .unwrap();
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                PACKAGES,
+                PACKAGES_GZ,
+                "httpuv_1.6.11.tgz",
+                "shiny_1.7.5.tgz"
+            ]
+        );
+        let index = std::fs::read_to_string(dir.join(PACKAGES)).unwrap();
+        let packages = crate::repo::parse_available_packages(&index).unwrap();
+        assert_eq!(packages.keys().collect::<Vec<_>>(), ["httpuv", "shiny"]);
+        assert!(matches!(
+            packages["shiny"].checksum(),
+            Some(Checksum::Md5(_))
+        ));
+
+        // A damaged tarball is downloaded again, the others are kept
+        std::fs::write(dir.join("httpuv_1.6.11.tgz"), "damaged").unwrap();
+        mirror(&mirror_args(source.path(), out.path(), "all"))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("httpuv_1.6.11.tgz")).unwrap(),
+            "httpuv tarball"
+        );
+        assert!(dir.join("leaflet_2.2.0.tgz").exists());
+
+        // The mirror can be used as a repository
+        let snapshot = tempfile::tempdir().unwrap();
+        mirror(&mirror_args(out.path(), snapshot.path(), "leaflet"))
+            .await
+            .unwrap();
+        assert!(snapshot
+            .path()
+            .join(contrib_path("4.3"))
+            .join("leaflet_2.2.0.tgz")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_partial_mirrors() {
+        let source = tempfile::tempdir().unwrap();
+        write_source(source.path(), None);
+        let out = tempfile::tempdir().unwrap();
+        let dir = out.path().join(contrib_path("4.3"));
+        for packages in ["shiny", "leaflet"] {
+            mirror(&mirror_args(source.path(), out.path(), packages))
+                .await
+                .unwrap();
+        }
+        let index = std::fs::read_to_string(dir.join(PACKAGES)).unwrap();
+        let packages = crate::repo::parse_available_packages(&index).unwrap();
+        assert_eq!(
+            packages.keys().collect::<Vec<_>>(),
+            ["httpuv", "leaflet", "shiny"]
+        );
+
+        // Damaged tarballs are left out of the index
+        std::fs::write(dir.join("httpuv_1.6.11.tgz"), "damaged").unwrap();
+        mirror(&mirror_args(source.path(), out.path(), "leaflet"))
+            .await
+            .unwrap();
+        let index = std::fs::read_to_string(dir.join(PACKAGES)).unwrap();
+        let packages = crate::repo::parse_available_packages(&index).unwrap();
+        assert_eq!(packages.keys().collect::<Vec<_>>(), ["leaflet", "shiny"]);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_checksum_mismatch() {
+        let source = tempfile::tempdir().unwrap();
+        write_source(source.path(), Some("httpuv"));
+        let out = tempfile::tempdir().unwrap();
+        let result = mirror(&mirror_args(source.path(), out.path(), "httpuv")).await;
+        assert!(matches!(result, Err(Error::Download { status: None, .. })));
+        let dir = out.path().join(contrib_path("4.3"));
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,131 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The display messages are printed above while downloads are running.
+static ACTIVE: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Turns progress bars on, done by the logger when progress messages are
+/// printed to a terminal.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Runs `print` with the progress bars cleared, so that messages don't get
+/// mixed with them.
+pub fn suspend<R>(print: impl FnOnce() -> R) -> R {
+    let active = ACTIVE.lock().expect("Progress lock poisoned").clone();
+    match active {
+        Some(multi) => multi.suspend(print),
+        None => print(),
+    }
+}
+
+/// Progress of the package downloads: a bar counting the packages and a
+/// line per running download. Nothing is drawn when progress bars are off,
+/// the downloads are then only reported by the log messages.
+#[derive(Clone)]
+pub struct DownloadProgress {
+    multi: MultiProgress,
+    overall: ProgressBar,
+}
+
+/// Progress of the download of one package.
+pub struct DownloadBar {
+    bar: ProgressBar,
+}
+
+impl DownloadProgress {
+    pub fn new(packages: usize) -> Self {
+        let enabled = ENABLED.load(Ordering::Relaxed);
+        let multi = MultiProgress::with_draw_target(if enabled {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        });
+        let overall = multi.add(ProgressBar::new(packages as u64));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "{prefix:.bold} [{bar:30.cyan/blue}] {pos}/{len} {elapsed}",
+            )
+            .expect("Invalid progress template")
+            .progress_chars("=> "),
+        );
+        overall.set_prefix("Downloading packages");
+        if enabled {
+            *ACTIVE.lock().expect("Progress lock poisoned") = Some(multi.clone());
+        }
+        Self { multi, overall }
+    }
+    /// Adds the line of a download, sized from the response's
+    /// `Content-Length` when the repository sends it.
+    pub fn start(&self, package: &str, size: Option<u64>) -> DownloadBar {
+        let bar = match size {
+            Some(size) => {
+                let bar = ProgressBar::new(size);
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "  {msg:20} {bytes:>10}/{total_bytes:<10} {binary_bytes_per_sec}",
+                    )
+                    .expect("Invalid progress template"),
+                );
+                bar
+            }
+            None => {
+                let bar = ProgressBar::new_spinner();
+                bar.set_style(
+                    ProgressStyle::with_template("  {msg:20} {bytes:>10} {binary_bytes_per_sec}")
+                        .expect("Invalid progress template"),
+                );
+                bar
+            }
+        };
+        let bar = self.multi.add(bar);
+        bar.set_message(package.to_string());
+        bar.enable_steady_tick(Duration::from_millis(200));
+        DownloadBar { bar }
+    }
+    /// Removes the line of a finished download and counts the package.
+    pub fn finish(&self, download: DownloadBar) {
+        download.bar.finish_and_clear();
+        self.multi.remove(&download.bar);
+        self.overall.inc(1);
+    }
+    /// Counts a package that was already downloaded or whose download
+    /// failed before starting.
+    pub fn skip(&self) {
+        self.overall.inc(1);
+    }
+    /// Clears the display once every download is done.
+    pub fn clear(&self) {
+        self.overall.finish_and_clear();
+        *ACTIVE.lock().expect("Progress lock poisoned") = None;
+    }
+}
+
+impl DownloadBar {
+    pub fn inc(&self, bytes: u64) {
+        self.bar.inc(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_progress() {
+        let progress = DownloadProgress::new(3);
+        for size in [Some(1024), None] {
+            let bar = progress.start("shiny (1.7.5)", size);
+            bar.inc(512);
+            progress.finish(bar);
+        }
+        progress.skip();
+        assert_eq!(progress.overall.position(), 3);
+        progress.clear();
+    }
+}
@@ -1,4 +1,6 @@
-use crate::errors::BundlerResult;
+use crate::download::Checksum;
+use crate::errors::{BundlerResult, Error};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, BTreeSet},
@@ -17,6 +19,9 @@ pub struct Package {
     version: Arc<str>,
     #[serde(rename = "Hash")]
     hash: Arc<str>,
+    /// Checksum of the tarball, from the repository index.
+    #[serde(skip)]
+    checksum: Option<Checksum>,
 }
 
 impl Package {
@@ -29,12 +34,19 @@ impl Package {
             requirements: BTreeSet::new(),
             version: version.into(),
             hash: hash.into(),
+            checksum: None,
         }
     }
     pub fn add_requirement(&mut self, requirement: &str) {
         self.requirements.insert(requirement.into());
     }
-    pub fn get_requirements(&self) -> std::collections::btree_set::Iter<String> {
+    pub fn checksum(&self) -> Option<&Checksum> {
+        self.checksum.as_ref()
+    }
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = Some(checksum);
+    }
+    pub fn get_requirements(&self) -> std::collections::btree_set::Iter<'_, String> {
         self.requirements.iter()
     }
 }
@@ -46,18 +58,26 @@ impl std::fmt::Display for Package {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct RenvLock {
     #[serde(rename = "Packages")]
     packages: BTreeMap<String, Package>,
+    /// App whose lock file each merged package was taken from.
+    #[serde(skip)]
+    apps: BTreeMap<String, String>,
 }
 
 impl RenvLock {
     pub fn read_from_file(appdir: impl AsRef<std::path::Path>) -> BundlerResult<Self> {
-        let renv_lock = File::open(appdir.as_ref().join("renv.lock"))?;
-        Ok(serde_json::from_reader(renv_lock)?)
+        Self::read(appdir.as_ref().join("renv.lock"))
     }
-    pub fn packages(&self) -> std::collections::btree_map::Values<String, Package> {
+    /// Reads the lock file at `path`.
+    pub fn read(path: impl AsRef<std::path::Path>) -> BundlerResult<Self> {
+        let path = path.as_ref();
+        let renv_lock = File::open(path).map_err(Error::open_lockfile(path))?;
+        serde_json::from_reader(std::io::BufReader::new(renv_lock)).map_err(Error::lockfile(path))
+    }
+    pub fn packages(&self) -> std::collections::btree_map::Values<'_, String, Package> {
         self.packages.values()
     }
     pub fn packages_mut(&mut self) -> &mut BTreeMap<String, Package> {
@@ -66,4 +86,109 @@ impl RenvLock {
     pub fn contains(&self, package: &str) -> bool {
         self.packages.contains_key(package)
     }
+    pub fn package_names(&self) -> Vec<String> {
+        self.packages.keys().cloned().collect()
+    }
+    /// The packages `names` and everything they depend on, each package
+    /// after its dependencies. Packages missing from the lock file are left
+    /// out.
+    pub fn install_order(&self, names: &[String]) -> Vec<String> {
+        fn visit(
+            lock: &RenvLock,
+            name: &str,
+            visited: &mut BTreeSet<String>,
+            order: &mut Vec<String>,
+        ) {
+            let Some(package) = lock.packages.get(name) else {
+                return;
+            };
+            if !visited.insert(name.to_string()) {
+                return;
+            }
+            for requirement in package.get_requirements() {
+                visit(lock, requirement, visited, order);
+            }
+            order.push(name.to_string());
+        }
+        let mut visited = BTreeSet::new();
+        let mut order = Vec::new();
+        for name in names {
+            visit(self, name, &mut visited, &mut order);
+        }
+        order
+    }
+    /// Adds the packages of the lock file of `app`. A package locked to
+    /// another version by an earlier app is reported, as the apps share the
+    /// version resolved from the repository, which at least one of them
+    /// wasn't locked with.
+    pub fn merge(&mut self, app: &str, other: RenvLock) {
+        for (name, package) in other.packages {
+            match self.packages.get(&name) {
+                Some(kept) if kept.version != package.version => warn!(
+                    "{} is locked to {} by {} and to {} by {}, both apps get the version of the repository",
+                    name,
+                    kept.version,
+                    self.apps.get(&name).map_or("another app", String::as_str),
+                    package.version,
+                    app,
+                ),
+                Some(_) => {}
+                None => {
+                    self.apps.insert(name.clone(), app.to_string());
+                    self.packages.insert(name, package);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let lock = |packages: &[(&str, &str)]| {
+            let mut renv_lock = RenvLock::default();
+            for (name, version) in packages {
+                renv_lock
+                    .packages_mut()
+                    .insert(name.to_string(), Package::new(name, version, ""));
+            }
+            renv_lock
+        };
+        let mut renv_lock = lock(&[]);
+        renv_lock.merge("app1", lock(&[("shiny", "1.7.5"), ("bslib", "0.5.1")]));
+        renv_lock.merge("app2", lock(&[("shiny", "1.8.0"), ("DT", "0.30")]));
+        let versions = renv_lock
+            .packages()
+            .map(Package::get_package)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            [("DT", "0.30"), ("bslib", "0.5.1"), ("shiny", "1.7.5")]
+        );
+        assert_eq!(renv_lock.apps["shiny"], "app1");
+        assert_eq!(renv_lock.apps["DT"], "app2");
+    }
+
+    #[test]
+    fn test_install_order() {
+        let mut renv_lock = RenvLock::default();
+        for (name, requirements) in [
+            ("shiny", vec!["bslib", "httpuv"]),
+            ("bslib", vec!["htmltools"]),
+            ("httpuv", vec!["later"]),
+            ("htmltools", vec![]),
+            ("later", vec!["shiny"]),
+        ] {
+            let mut package = Package::new(name, "1.0", "");
+            requirements
+                .into_iter()
+                .for_each(|requirement| package.add_requirement(requirement));
+            renv_lock.packages_mut().insert(name.to_string(), package);
+        }
+        let order = renv_lock.install_order(&["shiny".to_string(), "missing".to_string()]);
+        assert_eq!(order, ["htmltools", "bslib", "later", "httpuv", "shiny"]);
+    }
 }
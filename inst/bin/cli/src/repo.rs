@@ -1,86 +1,109 @@
+use crate::download::Checksum;
 use crate::errors::{BundlerResult, Error};
+use crate::events::{emit, Event};
 use crate::renv::{Package, RenvLock};
+use crate::repository::PackageRepository;
 use colored::Colorize;
-use std::{
-    collections::{BTreeMap, BTreeSet},
-    io::Read,
-};
+use log::{debug, info};
+use std::collections::{BTreeMap, BTreeSet};
 
-use flate2::bufread::GzDecoder;
-
-const R_VERSION: &str = "4.3";
-const REPO: &str = "https://repo.r-wasm.org";
-
-fn get_packages_available_url() -> String {
-    format!("{}/bin/emscripten/contrib/{}/PACKAGES.gz", REPO, R_VERSION)
-}
-
-pub async fn available_packages(
-    client: reqwest::Client,
-) -> BundlerResult<BTreeMap<String, Package>> {
-    eprintln!("Downloading available packages...");
-    let res = client
-        .get(get_packages_available_url())
-        .send()
-        .await?
-        .bytes()
-        .await?;
-    let mut decoder = GzDecoder::new(res.as_ref());
-    let mut buffer = String::new();
-    decoder.read_to_string(&mut buffer)?;
-    Ok(parse_available_packages(&buffer)?)
-}
+/// Packages that come with R and are never downloaded.
+const BASE_PACKAGES: &[&str] = &[
+    "R",
+    "base",
+    "compiler",
+    "datasets",
+    "graphics",
+    "grDevices",
+    "grid",
+    "methods",
+    "parallel",
+    "splines",
+    "stats",
+    "stats4",
+    "tcltk",
+    "tools",
+    "utils",
+];
 
 fn parse_depends(raw: &str) -> BTreeSet<String> {
     raw.split(',')
         .map(|s| s.trim())
-        .filter_map(|s| s.split_whitespace().next())
+        .filter_map(|s| s.split(|c: char| c.is_whitespace() || c == '(').next())
         .filter(|s| !s.is_empty())
-        .filter(|d| d.starts_with("R.") || d.eq(&"R"))
+        .filter(|d| !BASE_PACKAGES.contains(d))
         .map(|dependency| dependency.to_string())
         .collect()
 }
 
-fn parse_available_packages(raw: &str) -> BundlerResult<BTreeMap<String, Package>> {
+/// Splits a PACKAGES entry into its fields, joining continuation lines.
+fn parse_fields(block: &str) -> BTreeMap<&str, String> {
+    let mut fields: BTreeMap<&str, String> = BTreeMap::new();
+    let mut last = None;
+    for line in block.lines() {
+        if line.starts_with(char::is_whitespace) {
+            if let Some(value) = last.and_then(|field| fields.get_mut(field)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((field, value)) = line.split_once(':') {
+            fields.insert(field, value.trim().to_string());
+            last = Some(field);
+        }
+    }
+    fields
+}
+
+pub(crate) fn parse_available_packages(raw: &str) -> BundlerResult<BTreeMap<String, Package>> {
     let mut packages = BTreeMap::new();
-    for block in raw.split("\n\n") {
-        let mut lines = block.lines();
-        let package = lines
-            .next()
-            .ok_or(Error::PackageParseError("Package not found"))?
-            .split_whitespace()
-            .last()
+    for block in raw.split("\n\n").filter(|block| !block.trim().is_empty()) {
+        let fields = parse_fields(block);
+        let name = fields
+            .get("Package")
             .ok_or(Error::PackageParseError("Package name not found"))?;
-        let version = lines
-            .next()
-            .ok_or(Error::PackageParseError("Package not found"))?
-            .split_whitespace()
-            .last()
+        let version = fields
+            .get("Version")
             .ok_or(Error::PackageParseError("Package version not found"))?;
-        let mut package = Package::new(package, version, "");
-        // Get the dependencies
-        for line in lines {
-            if let Some((key, value)) = line.split_once(':') {
-                match key {
-                    "Depends" | "Imports" => parse_depends(value)
-                        .into_iter()
-                        .for_each(|dependency| package.add_requirement(&dependency)),
-                    _ => (),
-                }
+        let mut package = Package::new(name, version, "");
+        if let Some(sha256) = fields.get("SHA256") {
+            package.set_checksum(Checksum::Sha256(sha256.to_lowercase()));
+        } else if let Some(md5) = fields.get("MD5sum") {
+            package.set_checksum(Checksum::Md5(md5.to_lowercase()));
+        }
+        // LinkingTo is only needed to compile packages
+        for field in ["Depends", "Imports"] {
+            if let Some(value) = fields.get(field) {
+                parse_depends(value)
+                    .into_iter()
+                    .for_each(|dependency| package.add_requirement(&dependency));
             }
         }
-        packages.insert(package.get_package().0.to_string(), package);
+        packages.insert(name.to_string(), package);
     }
     Ok(packages)
 }
 
-pub struct VesionMatcher {
-    available_packages: BTreeMap<String, Package>,
+/// The entries of the PACKAGES index `raw` describing the packages `names`.
+pub(crate) fn select_available_packages(raw: &str, names: &BTreeSet<String>) -> String {
+    let blocks = raw
+        .split("\n\n")
+        .filter(|block| {
+            parse_fields(block)
+                .get("Package")
+                .is_some_and(|name| names.contains(name))
+        })
+        .map(|block| block.trim_matches('\n'))
+        .collect::<Vec<_>>();
+    format!("{}\n", blocks.join("\n\n"))
 }
 
-impl VesionMatcher {
-    pub async fn new(client: reqwest::Client) -> BundlerResult<Self> {
-        let available_packages = available_packages(client).await?;
+pub struct VesionMatcher<'a> {
+    available_packages: &'a BTreeMap<String, Package>,
+}
+
+impl<'a> VesionMatcher<'a> {
+    pub async fn new(repository: &'a PackageRepository) -> BundlerResult<Self> {
+        let available_packages = repository.packages().await?;
         Ok(Self { available_packages })
     }
     // Update Renv
@@ -98,7 +121,12 @@ impl VesionMatcher {
                     renv_packages.insert(key, available.clone());
                 }
                 None => {
-                    eprintln!(
+                    let (name, version) = package.get_package();
+                    emit(Event::PackageUnavailable {
+                        package: name.to_string(),
+                        version: version.to_string(),
+                    });
+                    info!(
                         "Package {} not available removing from download list",
                         package.to_string().yellow().italic()
                     );
@@ -113,11 +141,17 @@ impl VesionMatcher {
             return;
         }
         requirements.iter().for_each(|dependency| {
+            // Packages already in the lock file have been resolved
+            if renv_lock.contains(dependency) {
+                return;
+            }
             if let Some(available) = self.available_packages.get(dependency.as_str()) {
-                self.insert_pkg_deps(available, renv_lock);
+                debug!("Adding {}, a dependency of {}", available, pkg);
+                // Inserted first so that dependency cycles end here
                 renv_lock
                     .packages_mut()
                     .insert(dependency.to_string(), available.clone());
+                self.insert_pkg_deps(available, renv_lock);
             } else {
                 renv_lock.packages_mut().remove(dependency.as_str());
             }
@@ -132,3 +166,68 @@ impl VesionMatcher {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_available_packages() {
+        let raw = "Package: bslib\nVersion: 0.5.1\nDepends: R (>= 2.10)\nImports: base64enc, cachem, grDevices, htmltools (>=\n        0.5.4), jquerylib (>= 0.1.3)\nLicense: MIT\n\nPackage: cachem\nVersion: 1.0.8\nImports: rlang, fastmap\nMD5sum: 0CC175B9C0F1B6A831C399E269772661\n";
+        let packages = parse_available_packages(raw).unwrap();
+        let requirements = packages["bslib"].get_requirements().collect::<Vec<_>>();
+        assert_eq!(
+            requirements,
+            ["base64enc", "cachem", "htmltools", "jquerylib"]
+        );
+        assert_eq!(packages["cachem"].get_package(), ("cachem", "1.0.8"));
+        assert_eq!(
+            packages["cachem"].checksum(),
+            Some(&Checksum::Md5(
+                "0cc175b9c0f1b6a831c399e269772661".to_string()
+            ))
+        );
+        assert_eq!(packages["bslib"].checksum(), None);
+        let selected = select_available_packages(raw, &BTreeSet::from(["cachem".to_string()]));
+        assert_eq!(
+            parse_available_packages(&selected)
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["cachem"]
+        );
+    }
+
+    #[test]
+    fn test_sync_renv() {
+        let raw = "Package: shiny\nVersion: 1.7.5\nDepends: R (>= 3.0.2), methods\nImports: httpuv (>= 1.5.2), later\n\n\
+                   Package: httpuv\nVersion: 1.6.11\nImports: later, Rcpp\n\n\
+                   Package: later\nVersion: 1.3.1\nImports: Rcpp, shiny\n\n\
+                   Package: Rcpp\nVersion: 1.0.11\n\n\
+                   Package: bslib\nVersion: 0.5.1\n";
+        let version_matcher = VesionMatcher {
+            available_packages: &parse_available_packages(raw).unwrap(),
+        };
+        let mut renv_lock = RenvLock::default();
+        for (name, version) in [("shiny", "1.7.4"), ("bslib", "0.4.0"), ("rJava", "1.0")] {
+            renv_lock
+                .packages_mut()
+                .insert(name.to_string(), Package::new(name, version, ""));
+        }
+        version_matcher.sync_renv(&mut renv_lock);
+        let resolved = renv_lock
+            .packages()
+            .map(|package| package.get_package())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            resolved,
+            [
+                ("Rcpp", "1.0.11"),
+                ("bslib", "0.5.1"),
+                ("httpuv", "1.6.11"),
+                ("later", "1.3.1"),
+                ("shiny", "1.7.5"),
+            ]
+        );
+    }
+}
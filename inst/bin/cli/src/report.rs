@@ -0,0 +1,240 @@
+use crate::build::APPS_DIR;
+use crate::bundle::{APP_FILES_DIR, APP_FILES_MANIFEST, APP_IMAGE_DATA, APP_IMAGE_METADATA};
+use crate::cli::ArchiveFormat;
+use crate::errors::{BundlerResult, Error};
+use crate::library::{LIBRARY_DATA, LIBRARY_METADATA};
+use crate::size::format_size;
+use crate::webr::VENDOR_DIR;
+use clap::ValueEnum;
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+/// What a file of the bundle is part of.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeKind {
+    /// The archive of an app.
+    App,
+    /// A package tarball of the repository.
+    Package,
+    /// The vendored webR runtime.
+    WebR,
+    /// The index files of the package repository.
+    Index,
+    /// The image of the pre-installed package library.
+    Library,
+    /// Pages, scripts and the other generated files.
+    Page,
+}
+
+impl std::fmt::Display for SizeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeKind::App => write!(f, "app"),
+            SizeKind::Package => write!(f, "package"),
+            SizeKind::WebR => write!(f, "webR"),
+            SizeKind::Index => write!(f, "index"),
+            SizeKind::Library => write!(f, "library"),
+            SizeKind::Page => write!(f, "page"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SizeEntry {
+    kind: SizeKind,
+    name: String,
+    /// Bytes on disk, what a browser downloads.
+    compressed: u64,
+    /// Bytes once the archives are extracted.
+    uncompressed: u64,
+}
+
+/// Sizes of everything in a built bundle.
+#[derive(Serialize, Debug)]
+pub struct SizeReport {
+    entries: Vec<SizeEntry>,
+    compressed: u64,
+    uncompressed: u64,
+}
+
+/// Size of the contents of a compressed file, or `None` when it is not
+/// compressed or can't be read.
+fn extracted_size(path: &Path) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    let mut decoder: Box<dyn std::io::Read> = match path.extension()?.to_str()? {
+        "tgz" | "gz" => Box::new(GzDecoder::new(file)),
+        "xz" => Box::new(xz2::read::XzDecoder::new(file)),
+        _ => return None,
+    };
+    std::io::copy(&mut decoder, &mut std::io::sink()).ok()
+}
+
+/// The app whose files `name` holds, if any.
+fn app_of(name: &Path) -> Option<String> {
+    let parts = name
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let (app, rest) = match parts.as_slice() {
+        [apps, app, rest @ ..] if apps == APPS_DIR => (app.clone(), rest),
+        rest => ("app".to_string(), rest),
+    };
+    let is_payload = match rest {
+        [file] => {
+            [APP_IMAGE_DATA, APP_IMAGE_METADATA, APP_FILES_MANIFEST].contains(&file.as_str())
+                || ArchiveFormat::value_variants()
+                    .iter()
+                    .any(|format| file == format.file_name())
+        }
+        [dir, _, ..] => dir == APP_FILES_DIR,
+        [] => false,
+    };
+    is_payload.then_some(app)
+}
+
+fn classify(name: &Path) -> (SizeKind, String) {
+    let first = name
+        .components()
+        .next()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .unwrap_or_default();
+    let is_tgz = name.extension().is_some_and(|ext| ext == "tgz");
+    if let Some(app) = app_of(name) {
+        (SizeKind::App, app)
+    } else if first == "repo" && is_tgz {
+        let package = name.file_stem().unwrap_or_default().to_string_lossy();
+        (SizeKind::Package, package.to_string())
+    } else if first == "repo" {
+        (SizeKind::Index, "repository index".to_string())
+    } else if first == LIBRARY_DATA || first == LIBRARY_METADATA {
+        (SizeKind::Library, "package library image".to_string())
+    } else if first == VENDOR_DIR {
+        (SizeKind::WebR, "webR runtime".to_string())
+    } else {
+        (SizeKind::Page, "pages and scripts".to_string())
+    }
+}
+
+fn visit(
+    outdir: &Path,
+    dir: &Path,
+    sizes: &mut BTreeMap<(SizeKind, String), (u64, u64)>,
+) -> BundlerResult<()> {
+    for entry in std::fs::read_dir(dir).map_err(Error::bundle(dir))? {
+        let path = entry.map_err(Error::bundle(dir))?.path();
+        let metadata = std::fs::metadata(&path).map_err(Error::bundle(&path))?;
+        if metadata.is_dir() {
+            visit(outdir, &path, sizes)?;
+            continue;
+        }
+        let name = path.strip_prefix(outdir)?;
+        if name == Path::new(".webrignore") {
+            continue;
+        }
+        let compressed = metadata.len();
+        let uncompressed = extracted_size(&path).unwrap_or(compressed);
+        let size = sizes.entry(classify(name)).or_default();
+        size.0 += compressed;
+        size.1 += uncompressed;
+    }
+    Ok(())
+}
+
+impl SizeReport {
+    /// Measures the bundle written to `outdir`.
+    pub fn from_dir(outdir: impl AsRef<Path>) -> BundlerResult<Self> {
+        let mut sizes = BTreeMap::new();
+        visit(outdir.as_ref(), outdir.as_ref(), &mut sizes)?;
+        let entries = sizes
+            .into_iter()
+            .map(|((kind, name), (compressed, uncompressed))| SizeEntry {
+                kind,
+                name,
+                compressed,
+                uncompressed,
+            })
+            .collect::<Vec<_>>();
+        Ok(Self {
+            compressed: entries.iter().map(|entry| entry.compressed).sum(),
+            uncompressed: entries.iter().map(|entry| entry.uncompressed).sum(),
+            entries,
+        })
+    }
+    /// What the bundle takes to download.
+    pub fn compressed(&self) -> u64 {
+        self.compressed
+    }
+    pub fn uncompressed(&self) -> u64 {
+        self.uncompressed
+    }
+    pub fn print(&self) {
+        info!("{}", "Bundle size:".bold());
+        for entry in &self.entries {
+            info!(
+                "  {:>10}  {:>10}  {:<8} {}",
+                format_size(entry.compressed),
+                format_size(entry.uncompressed),
+                entry.kind.to_string(),
+                entry.name
+            );
+        }
+        info!(
+            "  {:>10}  {:>10}  {}",
+            format_size(self.compressed).cyan().bold(),
+            format_size(self.uncompressed).cyan(),
+            "total (compressed, uncompressed)".bold()
+        );
+    }
+    pub fn write(&self, path: impl AsRef<Path>) -> BundlerResult<()> {
+        let path = path.as_ref();
+        std::fs::write(path, serde_json::to_string_pretty(self)?).map_err(Error::bundle(path))
+    }
+    /// Fails when the bundle takes more than `max_size` bytes to download.
+    pub fn check_budget(&self, max_size: u64) -> BundlerResult<()> {
+        if self.compressed > max_size {
+            return Err(Error::SizeBudgetExceeded {
+                size: self.compressed,
+                max_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_classify() {
+        let classify = |name: &str| classify(&PathBuf::from(name));
+        assert_eq!(classify("app.tgz"), (SizeKind::App, "app".into()));
+        assert_eq!(
+            classify("apps/sales/app.tar.xz"),
+            (SizeKind::App, "sales".into())
+        );
+        assert_eq!(classify("app/www/style.css"), (SizeKind::App, "app".into()));
+        assert_eq!(
+            classify("apps/sales/app.data"),
+            (SizeKind::App, "sales".into())
+        );
+        assert_eq!(
+            classify("repo/bin/emscripten/contrib/4.3/shiny_1.7.5.tgz"),
+            (SizeKind::Package, "shiny_1.7.5".into())
+        );
+        assert_eq!(
+            classify("repo/bin/emscripten/contrib/4.3/PACKAGES.rds").0,
+            SizeKind::Index
+        );
+        assert_eq!(classify("webr/R.bin.wasm").0, SizeKind::WebR);
+        assert_eq!(classify("library.data").0, SizeKind::Library);
+        assert_eq!(classify("index.html").0, SizeKind::Page);
+    }
+}
@@ -0,0 +1,634 @@
+use crate::errors::{BundlerResult, Error};
+use crate::http::HttpClient;
+use crate::renv::Package;
+use crate::repo::parse_available_packages;
+use bytes::Bytes;
+use flate2::bufread::GzDecoder;
+use futures::future::BoxFuture;
+use log::{debug, info};
+use reqwest::{Response, StatusCode, Url};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, OnceCell};
+
+/// Repository of R packages built for webR.
+pub const DEFAULT_REPO: &str = "https://repo.r-wasm.org";
+
+/// Index the packages are resolved with.
+pub const PACKAGES_GZ: &str = "PACKAGES.gz";
+/// Index R reads first, copied into the bundled repository.
+pub const PACKAGES_RDS: &str = "PACKAGES.rds";
+
+/// Size of the chunks local files are read in, so that their downloads
+/// report progress like remote ones.
+const LOCAL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Path of the directory holding the packages built for `r_version`,
+/// relative to the root of a repository.
+pub fn contrib_path(r_version: &str) -> String {
+    format!("bin/emscripten/contrib/{}", r_version)
+}
+
+/// Where the files of a package repository come from. Files are named
+/// relative to the directory of the packages built for one R version, e.g.
+/// `PACKAGES.gz` or `shiny_1.7.5.tgz`.
+///
+/// Missing files fail with [`Error::Download`] and the status HTTP would
+/// answer, 404, so that every repository reports them the same way.
+pub trait Repository: Send + Sync {
+    /// URL or path of the file `name`, for messages.
+    fn location(&self, name: &str) -> String;
+    /// Starts reading the file `name`.
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<RepositoryFile>>;
+    /// Size of the file `name`, `None` when the repository does not report
+    /// it or does not have the file.
+    fn size<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<Option<u64>>>;
+}
+
+enum Body {
+    Http { client: HttpClient, res: Response },
+    Local(tokio::fs::File),
+}
+
+/// A file of a repository being read.
+pub struct RepositoryFile {
+    size: Option<u64>,
+    body: Body,
+}
+
+impl RepositoryFile {
+    /// Size announced by the repository, if any.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+    /// Next chunk of the file, `None` once it is complete.
+    pub async fn chunk(&mut self) -> BundlerResult<Option<Bytes>> {
+        match &mut self.body {
+            Body::Http { client, res } => client.chunk(res).await,
+            Body::Local(file) => {
+                let mut chunk = Vec::with_capacity(LOCAL_CHUNK_SIZE);
+                file.take(LOCAL_CHUNK_SIZE as u64)
+                    .read_to_end(&mut chunk)
+                    .await
+                    .map_err(Error::Io)?;
+                Ok((!chunk.is_empty()).then(|| chunk.into()))
+            }
+        }
+    }
+    /// The rest of the file.
+    pub async fn bytes(self) -> BundlerResult<Bytes> {
+        match self.body {
+            Body::Http { client, res } => Ok(client.body(res).await?.into()),
+            Body::Local(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).await.map_err(Error::Io)?;
+                Ok(bytes.into())
+            }
+        }
+    }
+}
+
+fn not_found(name: &str, location: String) -> Error {
+    Error::Download {
+        package: name.to_string(),
+        url: location,
+        status: Some(StatusCode::NOT_FOUND.as_u16()),
+        reason: StatusCode::NOT_FOUND.to_string(),
+    }
+}
+
+/// A repository served over HTTP(S), like repo.r-wasm.org.
+pub struct HttpRepository {
+    client: HttpClient,
+    /// URL of the directory of the packages, without a trailing slash.
+    url: String,
+}
+
+impl HttpRepository {
+    /// The repository at `url`, holding the packages built for `r_version`
+    /// under `bin/emscripten/contrib/<r_version>`.
+    pub fn new(client: HttpClient, url: &str, r_version: &str) -> Self {
+        Self {
+            client,
+            url: format!("{}/{}", url.trim_end_matches('/'), contrib_path(r_version)),
+        }
+    }
+}
+
+impl Repository for HttpRepository {
+    fn location(&self, name: &str) -> String {
+        format!("{}/{}", self.url, name)
+    }
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<RepositoryFile>> {
+        Box::pin(async move {
+            let url = self.location(name);
+            let res = self
+                .client
+                .send(self.client.get(&url))
+                .await
+                .map_err(Error::download(name, &url))?;
+            match res.status() {
+                StatusCode::OK => Ok(RepositoryFile {
+                    size: res.content_length(),
+                    body: Body::Http {
+                        client: self.client.clone(),
+                        res,
+                    },
+                }),
+                status => Err(Error::Download {
+                    package: name.to_string(),
+                    url,
+                    status: Some(status.as_u16()),
+                    reason: status.to_string(),
+                }),
+            }
+        })
+    }
+    fn size<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<Option<u64>>> {
+        Box::pin(async move {
+            let url = self.location(name);
+            let res = self
+                .client
+                .send(self.client.head(&url))
+                .await
+                .map_err(Error::download(name, &url))?;
+            Ok(res
+                .headers()
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok())
+                .and_then(|length| length.parse().ok())
+                .filter(|_| res.status() == StatusCode::OK))
+        })
+    }
+}
+
+/// A repository on the local filesystem, e.g. the `repo/` directory of a
+/// bundle or a copy of repo.r-wasm.org.
+pub struct LocalRepository {
+    /// Directory of the packages.
+    dir: PathBuf,
+}
+
+impl LocalRepository {
+    /// The repository at `root`, holding the packages built for `r_version`
+    /// under `bin/emscripten/contrib/<r_version>`.
+    pub fn new(root: impl AsRef<Path>, r_version: &str) -> Self {
+        Self {
+            dir: root.as_ref().join(contrib_path(r_version)),
+        }
+    }
+}
+
+impl Repository for LocalRepository {
+    fn location(&self, name: &str) -> String {
+        self.dir.join(name).display().to_string()
+    }
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<RepositoryFile>> {
+        Box::pin(async move {
+            let location = self.location(name);
+            let file = match tokio::fs::File::open(self.dir.join(name)).await {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(not_found(name, location))
+                }
+                Err(err) => return Err(Error::download(name, &location)(err)),
+            };
+            let metadata = file
+                .metadata()
+                .await
+                .map_err(Error::download(name, &location))?;
+            Ok(RepositoryFile {
+                size: Some(metadata.len()),
+                body: Body::Local(file),
+            })
+        })
+    }
+    fn size<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<Option<u64>>> {
+        Box::pin(async move {
+            Ok(tokio::fs::metadata(self.dir.join(name))
+                .await
+                .ok()
+                .map(|metadata| metadata.len()))
+        })
+    }
+}
+
+/// A snapshot of a repository in a tar archive, compressed or not. The
+/// packages of the R version are extracted to a temporary directory, removed
+/// when the repository is dropped.
+pub struct ArchiveRepository {
+    archive: PathBuf,
+    prefix: String,
+    local: LocalRepository,
+    _dir: TempDir,
+}
+
+/// Name of the file of the archive entry `path` when it is in the directory
+/// of the packages, `contrib` being that directory split in components. The
+/// directory may be nested, e.g. in a directory named after the snapshot.
+fn contrib_entry(path: &Path, contrib: &[&str]) -> Option<String> {
+    let parts = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    let (name, dir) = parts.split_last()?;
+    dir.ends_with(contrib).then(|| name.to_string())
+}
+
+impl ArchiveRepository {
+    /// Extracts the packages built for `r_version` from the archive at
+    /// `path`, a `.tar`, `.tar.gz`, `.tgz`, `.tar.xz` or `.tar.zst` file.
+    pub fn new(path: impl AsRef<Path>, r_version: &str) -> BundlerResult<Self> {
+        let path = path.as_ref();
+        let location = path.display().to_string();
+        let file = File::open(path).map_err(Error::resolution(&location))?;
+        let name = path.to_string_lossy();
+        let reader: Box<dyn Read> = if name.ends_with(".tgz") || name.ends_with(".gz") {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else if name.ends_with(".zst") {
+            Box::new(zstd::Decoder::new(file).map_err(Error::resolution(&location))?)
+        } else if name.ends_with(".xz") {
+            Box::new(xz2::read::XzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let prefix = contrib_path(r_version);
+        let contrib = prefix.split('/').collect::<Vec<_>>();
+        let dir = tempfile::tempdir().map_err(Error::resolution(&location))?;
+        let local = LocalRepository {
+            dir: dir.path().to_path_buf(),
+        };
+        let mut files = 0;
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(Error::resolution(&location))? {
+            let mut entry = entry.map_err(Error::resolution(&location))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            if let Some(name) = contrib_entry(
+                &entry.path().map_err(Error::resolution(&location))?,
+                &contrib,
+            ) {
+                entry
+                    .unpack(local.dir.join(&name))
+                    .map_err(Error::resolution(&location))?;
+                files += 1;
+            }
+        }
+        if files == 0 {
+            return Err(Error::Resolution {
+                url: location,
+                status: None,
+                reason: format!("the archive has no {} directory", prefix),
+            });
+        }
+        debug!("Extracted {} files of {}", files, path.display());
+        Ok(Self {
+            archive: path.to_path_buf(),
+            prefix,
+            local,
+            _dir: dir,
+        })
+    }
+}
+
+impl Repository for ArchiveRepository {
+    fn location(&self, name: &str) -> String {
+        format!("{}!/{}/{}", self.archive.display(), self.prefix, name)
+    }
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<RepositoryFile>> {
+        Box::pin(async move {
+            self.local.open(name).await.map_err(|err| match err {
+                Error::Download {
+                    package,
+                    status,
+                    reason,
+                    ..
+                } => Error::Download {
+                    package,
+                    url: self.location(name),
+                    status,
+                    reason,
+                },
+                err => err,
+            })
+        })
+    }
+    fn size<'a>(&'a self, name: &'a str) -> BoxFuture<'a, BundlerResult<Option<u64>>> {
+        self.local.size(name)
+    }
+}
+
+/// The repository given to `--repo`: an `http(s)://` or `file://` URL, a
+/// local directory or an archive with a snapshot of a repository.
+pub fn open_repository(
+    spec: &str,
+    client: HttpClient,
+    r_version: &str,
+) -> BundlerResult<Box<dyn Repository>> {
+    if spec.starts_with("http://") || spec.starts_with("https://") {
+        return Ok(Box::new(HttpRepository::new(client, spec, r_version)));
+    }
+    let path = match spec.strip_prefix("file://") {
+        Some(_) => Url::parse(spec)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| Error::Resolution {
+                url: spec.to_string(),
+                status: None,
+                reason: "not a valid file URL".to_string(),
+            })?,
+        None => PathBuf::from(spec),
+    };
+    if path.is_dir() {
+        Ok(Box::new(LocalRepository::new(path, r_version)))
+    } else if path.is_file() {
+        Ok(Box::new(ArchiveRepository::new(path, r_version)?))
+    } else {
+        Err(Error::Resolution {
+            url: spec.to_string(),
+            status: None,
+            reason: "no such directory or archive".to_string(),
+        })
+    }
+}
+
+struct Inner {
+    repository: Box<dyn Repository>,
+    r_version: String,
+    index_files: Mutex<BTreeMap<String, Bytes>>,
+    packages: OnceCell<BTreeMap<String, Package>>,
+}
+
+/// The package repository of a build. Index files are fetched at most once
+/// and shared by the resolution, the downloads and the bundled repository.
+/// Clones share the cache.
+#[derive(Clone)]
+pub struct PackageRepository {
+    inner: Arc<Inner>,
+}
+
+impl PackageRepository {
+    /// The packages built for `r_version` in `repository`.
+    pub fn new(repository: Box<dyn Repository>, r_version: &str) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                repository,
+                r_version: r_version.to_string(),
+                index_files: Mutex::new(BTreeMap::new()),
+                packages: OnceCell::new(),
+            }),
+        }
+    }
+    pub fn r_version(&self) -> &str {
+        &self.inner.r_version
+    }
+    fn tarball_name(package: &Package) -> String {
+        let (name, version) = package.get_package();
+        format!("{}_{}.tgz", name, version)
+    }
+    pub fn tarball_url(&self, package: &Package) -> String {
+        self.inner.repository.location(&Self::tarball_name(package))
+    }
+    /// The index file `name` of the repository, fetched on first use.
+    pub async fn index_file(&self, name: &str) -> BundlerResult<Bytes> {
+        let mut index_files = self.inner.index_files.lock().await;
+        if let Some(file) = index_files.get(name) {
+            return Ok(file.clone());
+        }
+        let location = self.inner.repository.location(name);
+        debug!("Fetching {}", location);
+        let file = self
+            .inner
+            .repository
+            .open(name)
+            .await?
+            .bytes()
+            .await
+            .map_err(Error::download(name, &location))?;
+        index_files.insert(name.to_string(), file.clone());
+        Ok(file)
+    }
+    /// The text of the PACKAGES index of the repository.
+    pub async fn packages_index(&self) -> BundlerResult<String> {
+        let location = self.inner.repository.location(PACKAGES_GZ);
+        let index = self
+            .index_file(PACKAGES_GZ)
+            .await
+            .map_err(|err| match err {
+                Error::Download {
+                    url,
+                    status,
+                    reason,
+                    ..
+                } => Error::Resolution {
+                    url,
+                    status,
+                    reason,
+                },
+                err => err,
+            })?;
+        let mut buffer = String::new();
+        GzDecoder::new(index.as_ref())
+            .read_to_string(&mut buffer)
+            .map_err(Error::resolution(&location))?;
+        Ok(buffer)
+    }
+    async fn read_packages(&self) -> BundlerResult<BTreeMap<String, Package>> {
+        info!("Downloading available packages...");
+        let packages = parse_available_packages(&self.packages_index().await?)?;
+        debug!("{} packages are available for webR", packages.len());
+        Ok(packages)
+    }
+    /// Every package of the repository by name, read from its index.
+    pub async fn packages(&self) -> BundlerResult<&BTreeMap<String, Package>> {
+        self.inner
+            .packages
+            .get_or_try_init(|| self.read_packages())
+            .await
+    }
+    /// Starts downloading the tarball of `package`.
+    pub async fn tarball(&self, package: &Package) -> BundlerResult<RepositoryFile> {
+        let name = Self::tarball_name(package);
+        self.inner
+            .repository
+            .open(&name)
+            .await
+            .map_err(|err| match err {
+                Error::Download {
+                    url,
+                    status,
+                    reason,
+                    ..
+                } => Error::Download {
+                    package: package.to_string(),
+                    url,
+                    status,
+                    reason,
+                },
+                err => err,
+            })
+    }
+    /// Size of the tarball of `package`, `None` when the repository does not
+    /// report it.
+    pub async fn tarball_size(&self, package: &Package) -> BundlerResult<Option<u64>> {
+        self.inner
+            .repository
+            .size(&Self::tarball_name(package))
+            .await
+    }
+    /// Writes the index R reads into `dir`, the contrib directory of the
+    /// bundled repository. Repositories without `PACKAGES.rds`, like local
+    /// stand-ins, get their `PACKAGES.gz` copied instead, which R also reads.
+    pub async fn write_index(&self, dir: impl AsRef<Path>) -> BundlerResult<()> {
+        let (name, index) = match self.index_file(PACKAGES_RDS).await {
+            Ok(index) => (PACKAGES_RDS, index),
+            Err(Error::Download {
+                status: Some(404), ..
+            }) => (PACKAGES_GZ, self.index_file(PACKAGES_GZ).await?),
+            Err(err) => return Err(err),
+        };
+        let path = dir.as_ref().join(name);
+        std::fs::write(&path, index).map_err(Error::bundle(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::HttpArgs;
+    use clap::Parser;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const PACKAGES: &str = "Package: shiny\nVersion: 1.7.5\nImports: httpuv\n\n\
+                            Package: httpuv\nVersion: 1.6.11\n";
+
+    /// Writes a repository with an index and the tarball of shiny into the
+    /// directory `root`.
+    fn write_repository(root: &Path) {
+        let dir = root.join(contrib_path("4.3"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut index = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        index.write_all(PACKAGES.as_bytes()).unwrap();
+        std::fs::write(dir.join(PACKAGES_GZ), index.finish().unwrap()).unwrap();
+        std::fs::write(dir.join("shiny_1.7.5.tgz"), vec![7; 100_000]).unwrap();
+    }
+
+    /// Checks the packages of the repository written by `write_repository`,
+    /// returning the sizes of the chunks the tarball of shiny was read in.
+    async fn check_repository(repository: PackageRepository) -> Vec<usize> {
+        let packages = repository.packages().await.unwrap();
+        assert_eq!(packages.keys().collect::<Vec<_>>(), vec!["httpuv", "shiny"]);
+        let shiny = packages["shiny"].clone();
+        assert_eq!(
+            repository.tarball_size(&shiny).await.unwrap(),
+            Some(100_000)
+        );
+        let mut tarball = repository.tarball(&shiny).await.unwrap();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = tarball.chunk().await.unwrap() {
+            chunks.push(chunk.len());
+        }
+        assert_eq!(chunks.iter().sum::<usize>(), 100_000);
+        let httpuv = packages["httpuv"].clone();
+        assert_eq!(repository.tarball_size(&httpuv).await.unwrap(), None);
+        assert!(matches!(
+            repository.tarball(&httpuv).await,
+            Err(Error::Download {
+                status: Some(404),
+                ..
+            })
+        ));
+        chunks
+    }
+
+    /// Serves the files of `root` over HTTP until the test ends, answering
+    /// GET and HEAD requests, and returns the URL of the server.
+    async fn serve(root: PathBuf) -> String {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buffer = [0; 1024];
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let mut words = request.split_whitespace();
+                let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+                let (status, body) = match std::fs::read(root.join(path.trim_start_matches('/'))) {
+                    Ok(body) => ("200 OK", body),
+                    Err(_) => ("404 Not Found", Vec::new()),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                if method != "HEAD" {
+                    let _ = stream.write_all(&body).await;
+                }
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_http_repository() {
+        let root = tempfile::tempdir().unwrap();
+        write_repository(root.path());
+        let url = serve(root.path().to_path_buf()).await;
+        let args = HttpArgs::parse_from(["webr-bundle"]);
+        let client = HttpClient::new(&args, &Default::default(), ".").unwrap();
+        let repository = open_repository(&url, client, "4.3").unwrap();
+        check_repository(PackageRepository::new(repository, "4.3")).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_repository() {
+        let root = tempfile::tempdir().unwrap();
+        write_repository(root.path());
+        let url = Url::from_directory_path(root.path()).unwrap();
+        for spec in [root.path().display().to_string(), url.to_string()] {
+            let args = HttpArgs::parse_from(["webr-bundle"]);
+            let client = HttpClient::new(&args, &Default::default(), ".").unwrap();
+            let repository = open_repository(&spec, client, "4.3").unwrap();
+            let chunks = check_repository(PackageRepository::new(repository, "4.3")).await;
+            assert_eq!(chunks, vec![LOCAL_CHUNK_SIZE, 100_000 - LOCAL_CHUNK_SIZE]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_repository() {
+        let root = tempfile::tempdir().unwrap();
+        write_repository(&root.path().join("snapshot"));
+        let path = root.path().join("snapshot.tar.gz");
+        let mut archive = tar::Builder::new(GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        archive
+            .append_dir_all("snapshot", root.path().join("snapshot"))
+            .unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+        let repository = ArchiveRepository::new(&path, "4.3").unwrap();
+        check_repository(PackageRepository::new(Box::new(repository), "4.3")).await;
+        assert!(matches!(
+            ArchiveRepository::new(&path, "4.4"),
+            Err(Error::Resolution { .. })
+        ));
+    }
+}
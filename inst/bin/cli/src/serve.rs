@@ -4,22 +4,31 @@ use actix_web::{middleware::Logger, App, HttpServer};
 use std::path::{Path, PathBuf};
 
 fn check_if_outdir_exists(outdir: impl AsRef<Path>) -> BundlerResult<()> {
-    if outdir.as_ref().try_exists()? {
-        Ok(())
-    } else {
-        Err(Error::NoDistDir(outdir.as_ref().into()))
+    match outdir.as_ref().try_exists() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::NoDistDir(outdir.as_ref().into())),
+        Err(err) => Err(Error::Serve {
+            address: outdir.as_ref().display().to_string(),
+            reason: err.to_string(),
+        }),
     }
 }
 
 pub async fn server(outdir: PathBuf, port: u16) -> BundlerResult<()> {
     check_if_outdir_exists(&outdir)?;
+    let address = format!("127.0.0.1:{}", port);
+    let serve_error = |err: std::io::Error| Error::Serve {
+        address: address.clone(),
+        reason: err.to_string(),
+    };
     HttpServer::new(move || {
         App::new()
             .service(fs::Files::new("/", outdir.clone()).index_file("index.html"))
             .wrap(Logger::default().log_target("webr::server"))
     })
-    .bind(("127.0.0.1", port))?
+    .bind(("127.0.0.1", port))
+    .map_err(serve_error)?
     .run()
-    .await?;
-    Ok(())
+    .await
+    .map_err(serve_error)
 }
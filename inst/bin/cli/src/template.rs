@@ -0,0 +1,156 @@
+use crate::errors::{BundlerResult, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Values available to a template, keyed by variable name. Values are
+/// escaped when they are inserted, so rendering only has to splice them in.
+#[derive(Debug, Default)]
+pub struct Variables {
+    values: BTreeMap<&'static str, String>,
+}
+
+/// Serializes `value` as a JavaScript literal. JSON is valid JavaScript, the
+/// only difference being the line and paragraph separators which older
+/// engines reject inside string literals.
+pub fn js_literal<T: Serialize + ?Sized>(value: &T) -> BundlerResult<String> {
+    Ok(serde_json::to_string(value)?
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029"))
+}
+
+/// Escapes text so it can be placed in HTML content or attribute values.
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Inserts `value` as a JavaScript literal.
+    pub fn js<T: Serialize + ?Sized>(
+        mut self,
+        name: &'static str,
+        value: &T,
+    ) -> BundlerResult<Self> {
+        self.values.insert(name, js_literal(value)?);
+        Ok(self)
+    }
+    /// Inserts `value` as HTML-escaped text.
+    pub fn html(mut self, name: &'static str, value: &str) -> Self {
+        self.values.insert(name, html_escape(value));
+        self
+    }
+    /// Inserts `value` verbatim. Only use it for markup generated by us.
+    pub fn raw(mut self, name: &'static str, value: String) -> Self {
+        self.values.insert(name, value);
+        self
+    }
+}
+
+/// Renders a template, replacing every `{{ name }}` with the value of the
+/// variable `name`. Referencing a variable that was not provided is an
+/// error, so typos in templates don't silently produce broken output.
+pub fn render(template: &str, variables: &Variables) -> BundlerResult<String> {
+    render_with(template, variables, true)
+}
+
+/// Renders a user provided template. Only the provided variables are
+/// replaced, any other `{{ ... }}` is kept as is so the page can hold the
+/// markup of another template engine.
+pub fn render_known(template: &str, variables: &Variables) -> BundlerResult<String> {
+    render_with(template, variables, false)
+}
+
+/// Whether `template` references the variable `name`.
+pub fn uses_variable(template: &str, name: &str) -> bool {
+    template
+        .split("{{")
+        .skip(1)
+        .any(|part| matches!(part.split_once("}}"), Some((inner, _)) if inner.trim() == name))
+}
+
+fn render_with(template: &str, variables: &Variables, strict: bool) -> BundlerResult<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            if strict {
+                return Err(Error::Template("unclosed `{{` in template".to_string()));
+            }
+            output.push_str(&rest[start..]);
+            return Ok(output);
+        };
+        let name = after[..end].trim();
+        match variables.values.get(name) {
+            Some(value) => output.push_str(value),
+            None if strict => {
+                return Err(Error::Template(format!(
+                    "unknown template variable `{}`",
+                    name
+                )))
+            }
+            None => output.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_escapes_js() {
+        let variables = Variables::new()
+            .js("name", "it's a \"quote\"\n</script>")
+            .unwrap()
+            .js("list", &["a", "b"])
+            .unwrap();
+        let rendered = render("const x = {{ name }}; const y = {{list}};", &variables).unwrap();
+        assert_eq!(
+            rendered,
+            r#"const x = "it's a \"quote\"\n</script>"; const y = ["a","b"];"#
+        );
+    }
+
+    #[test]
+    fn test_render_escapes_html() {
+        let variables = Variables::new().html("title", "<b>Tom & \"Jerry\"</b>");
+        let rendered = render("<title>{{ title }}</title>", &variables).unwrap();
+        assert_eq!(
+            rendered,
+            "<title>&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;</title>"
+        );
+    }
+
+    #[test]
+    fn test_render_unknown_variable() {
+        assert!(render("{{ missing }}", &Variables::new()).is_err());
+        assert!(render("{{ unclosed", &Variables::new()).is_err());
+    }
+
+    #[test]
+    fn test_render_known() {
+        let variables = Variables::new().html("title", "App");
+        let rendered = render_known("{{ title }}: {{ user.name }} {{ open", &variables).unwrap();
+        assert_eq!(rendered, "App: {{ user.name }} {{ open");
+        assert!(uses_variable("<body>{{scripts}}</body>", "scripts"));
+        assert!(!uses_variable("<body>{{ script }}</body>", "scripts"));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::build::{build, build_app};
+use crate::bundle::{build_walker, BundleOptions};
+use crate::cli::BuildArgs;
+use crate::config::CONFIG_FILE;
+use crate::errors::{BundlerResult, Error};
+use colored::Colorize;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files whose changes affect more than the app tarball.
+const FULL_REBUILD_FILES: &[&str] = &["renv.lock", CONFIG_FILE];
+
+type Snapshot = BTreeMap<PathBuf, (SystemTime, u64)>;
+
+/// Modification time and size of every file of the apps in `appdirs` that
+/// goes into the bundle.
+fn snapshot(appdirs: &[PathBuf], options: &BundleOptions) -> BundlerResult<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    for appdir in appdirs {
+        snapshot.extend(
+            build_walker(appdir, options)?
+                .filter_map(Result::ok)
+                .filter_map(|entry| {
+                    let metadata = entry.metadata().ok()?;
+                    let modified = metadata.modified().ok()?;
+                    metadata
+                        .is_file()
+                        .then(|| (entry.into_path(), (modified, metadata.len())))
+                }),
+        );
+    }
+    Ok(snapshot)
+}
+
+fn changed_files(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    let mut changed = new
+        .iter()
+        .filter(|(path, state)| old.get(*path) != Some(state))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    changed.extend(old.keys().filter(|path| !new.contains_key(*path)).cloned());
+    changed
+}
+
+/// A directory next to `outdir`, hidden so it's never bundled.
+fn sibling(outdir: &Path, suffix: &str) -> PathBuf {
+    let name = outdir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "dist".to_string());
+    outdir.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Replaces `outdir` with `staging`.
+fn swap_dir(staging: &Path, outdir: &Path) -> BundlerResult<()> {
+    let previous = sibling(outdir, "previous");
+    if previous.exists() {
+        std::fs::remove_dir_all(&previous).map_err(Error::bundle(&previous))?;
+    }
+    std::fs::rename(outdir, &previous).map_err(Error::bundle(outdir))?;
+    std::fs::rename(staging, outdir).map_err(Error::bundle(outdir))?;
+    std::fs::remove_dir_all(&previous).map_err(Error::bundle(&previous))
+}
+
+/// Builds into a directory next to the served one and swaps it in once the
+/// build succeeds, so the previous bundle stays served in the meantime and
+/// after a failed build.
+async fn build_and_swap(build_args: &BuildArgs) -> BundlerResult<()> {
+    let outdir = build_args.outdir();
+    let staging = sibling(outdir, "next");
+    build(&build_args.with_outdir(&staging)).await?;
+    swap_dir(&staging, outdir)
+}
+
+async fn rebuild(build_args: &BuildArgs, changed: &[PathBuf]) -> BundlerResult<()> {
+    let full_rebuild = changed.iter().any(|path| {
+        path.file_name()
+            .is_some_and(|name| FULL_REBUILD_FILES.iter().any(|file| name == *file))
+    });
+    if full_rebuild {
+        return build_and_swap(build_args).await;
+    }
+    for appdir in build_args.appdirs() {
+        if changed.iter().any(|path| path.starts_with(appdir)) {
+            build_app(build_args, appdir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Polls the app directories and rebuilds the bundle whenever a file
+/// changes. Changes to `renv.lock` or the configuration file trigger a full
+/// build, any other change only rebuilds the tarball of the app it belongs
+/// to. Build errors are printed and watching continues.
+pub async fn watch(build_args: BuildArgs) {
+    let appdirs = build_args.appdirs();
+    let options = build_args.bundle_options();
+    let mut last = snapshot(appdirs, &options).unwrap_or_default();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let current = match snapshot(appdirs, &options) {
+            Ok(current) => current,
+            Err(err) => {
+                error!("{}", err);
+                continue;
+            }
+        };
+        let changed = changed_files(&last, &current);
+        last = current;
+        if changed.is_empty() {
+            continue;
+        }
+        info!(
+            "{} changed, rebuilding...",
+            changed
+                .iter()
+                .map(|path| path.display().to_string().green().bold().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if let Err(err) = rebuild(&build_args, &changed).await {
+            error!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_rebuild_app() {
+        let dir = tempfile::tempdir().unwrap();
+        let appdirs = ["first", "second"].map(|name| dir.path().join(name));
+        for appdir in &appdirs {
+            std::fs::create_dir(appdir).unwrap();
+            std::fs::write(appdir.join("app.R"), "shinyApp(ui, server)").unwrap();
+        }
+        let outdir = dir.path().join("dist");
+        let build_args = BuildArgs::parse_from([
+            "build".as_ref(),
+            "--appdir".as_ref(),
+            appdirs[0].as_os_str(),
+            appdirs[1].as_os_str(),
+            "--outdir".as_ref(),
+            outdir.as_os_str(),
+        ]);
+        let options = build_args.bundle_options();
+        let before = snapshot(build_args.appdirs(), &options).unwrap();
+        std::fs::write(appdirs[1].join("app.R"), "shinyApp(ui, server) ").unwrap();
+        let changed = changed_files(&before, &snapshot(build_args.appdirs(), &options).unwrap());
+        assert_eq!(changed, [appdirs[1].join("app.R")]);
+        rebuild(&build_args, &changed).await.unwrap();
+        assert!(outdir.join("apps/second/app.tgz").is_file());
+        assert!(!outdir.join("apps/first").exists());
+        assert!(!outdir.join("app.tgz").exists());
+    }
+
+    #[test]
+    fn test_swap_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let outdir = dir.path().join("dist");
+        let staging = sibling(&outdir, "next");
+        assert_eq!(staging, dir.path().join(".dist.next"));
+        std::fs::create_dir(&outdir).unwrap();
+        std::fs::write(outdir.join("index.html"), "old").unwrap();
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::write(staging.join("index.html"), "new").unwrap();
+        swap_dir(&staging, &outdir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(outdir.join("index.html")).unwrap(),
+            "new"
+        );
+        assert!(!staging.exists());
+        assert!(!sibling(&outdir, "previous").exists());
+    }
+}
@@ -0,0 +1,528 @@
+use crate::errors::{BundlerResult, Error};
+use crate::http::HttpClient;
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use reqwest::StatusCode;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// webR release used when no version is given on the command line.
+pub const DEFAULT_WEBR_VERSION: &str = "v0.2.2";
+const WEBR_CDN: &str = "https://webr.r-wasm.org";
+pub const VENDOR_DIR: &str = "webr";
+const CACHE_COMPLETE_MARKER: &str = ".complete";
+
+/// Release archives with the whole webR distribution, including the
+/// `vfs/` tree the CDN can't list.
+const WEBR_RELEASES: &str = "https://github.com/r-wasm/webr/releases";
+const WEBR_LATEST_RELEASE: &str = "https://api.github.com/repos/r-wasm/webr/releases/latest";
+
+/// Files every webR distribution has. The worker loads the Emscripten
+/// output as `R.bin.js` and `R.bin.wasm`.
+const WEBR_REQUIRED_FILES: &[&str] = &["webr.mjs", "webr-worker.js", "R.bin.js", "R.bin.wasm"];
+
+/// R contrib version each webR release series was built against. Packages
+/// from a different contrib directory cannot be loaded by that runtime.
+const R_CONTRIB_VERSIONS: &[(&str, &str)] = &[
+    ("v0.1.", "4.1"),
+    ("v0.2.", "4.3"),
+    ("v0.3.", "4.3"),
+    ("v0.4.", "4.4"),
+    ("v0.5.", "4.5"),
+];
+
+/// Normalizes a webR version to the form used by the CDN, e.g. `0.2.1`
+/// becomes `v0.2.1`. `latest` is kept as is.
+pub fn normalize_version(version: &str) -> String {
+    let version = version.trim();
+    if version == "latest" || version.starts_with('v') {
+        version.to_string()
+    } else {
+        format!("v{}", version)
+    }
+}
+
+/// First webR release whose `webR.FS.mount` mounts filesystem images.
+const FS_MOUNT_VERSION: [u32; 3] = [0, 3, 0];
+
+/// The numbers of a version like `v0.2.2` or `v0.3.0-rc.1`.
+fn version_numbers(webr_version: &str) -> Option<Vec<u32>> {
+    webr_version
+        .trim_start_matches('v')
+        .split('-')
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Checks that the webR release can mount the filesystem images `feature`
+/// ships. Versions we can't read only produce a warning.
+pub fn check_mount_support(webr_version: &str, feature: &'static str) -> BundlerResult<()> {
+    if webr_version == "latest" {
+        return Ok(());
+    }
+    match version_numbers(webr_version) {
+        Some(numbers) if numbers.as_slice() < FS_MOUNT_VERSION.as_slice() => {
+            Err(Error::UnsupportedWebRFeature {
+                feature,
+                webr_version: webr_version.to_string(),
+            })
+        }
+        Some(_) => Ok(()),
+        None => {
+            warn!(
+                "Unknown webR version {}, unable to check that it supports {}",
+                webr_version.yellow(),
+                feature
+            );
+            Ok(())
+        }
+    }
+}
+
+fn r_contrib_version(webr_version: &str) -> Option<&'static str> {
+    R_CONTRIB_VERSIONS
+        .iter()
+        .find(|(prefix, _)| webr_version.starts_with(prefix))
+        .map(|(_, r_version)| *r_version)
+}
+
+/// Checks that the packages built for `r_version` can be loaded by the
+/// given webR release. Versions we know nothing about only produce a warning.
+pub fn check_compatibility(webr_version: &str, r_version: &str) -> BundlerResult<()> {
+    if webr_version == "latest" {
+        warn!(
+            "webR {} is not reproducible and can't be checked against R {}",
+            "latest".yellow(),
+            r_version
+        );
+        return Ok(());
+    }
+    match r_contrib_version(webr_version) {
+        Some(expected) if expected == r_version => Ok(()),
+        Some(expected) => Err(Error::IncompatibleWebR {
+            webr_version: webr_version.to_string(),
+            r_version: r_version.to_string(),
+            expected,
+        }),
+        None => {
+            warn!(
+                "Unknown webR version {}, unable to check compatibility with R {}",
+                webr_version.yellow(),
+                r_version
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Where the generated page loads the webR runtime from.
+#[derive(Debug, Clone)]
+pub enum WebRSource {
+    /// Loaded at runtime from the public webR CDN.
+    Cdn { version: String },
+    /// Shipped inside the bundle's `webr` directory.
+    Vendored,
+}
+
+impl WebRSource {
+    pub fn cdn(version: &str) -> Self {
+        WebRSource::Cdn {
+            version: version.to_string(),
+        }
+    }
+    /// Base URL of the webR distribution. It always ends with a slash so
+    /// file names can be appended to it.
+    pub fn base_url(&self) -> String {
+        self.url_from(0)
+    }
+    /// Base URL of the webR distribution as seen from a page `depth`
+    /// directories below the root of the bundle.
+    pub fn url_from(&self, depth: usize) -> String {
+        match self {
+            WebRSource::Cdn { version } => format!("{}/{}/", WEBR_CDN, version),
+            WebRSource::Vendored if depth == 0 => format!("./{}/", VENDOR_DIR),
+            WebRSource::Vendored => format!("{}{}/", "../".repeat(depth), VENDOR_DIR),
+        }
+    }
+}
+
+fn cache_dir(version: &str) -> Option<PathBuf> {
+    // `latest` moves over time, caching it would pin whatever was
+    // downloaded first.
+    if version == "latest" {
+        return None;
+    }
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("webr-bundle").join("webr").join(version))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> BundlerResult<()> {
+    std::fs::create_dir_all(to).map_err(Error::bundle(to))?;
+    for entry in std::fs::read_dir(from).map_err(Error::bundle(from))? {
+        let path = entry.map_err(Error::bundle(from))?.path();
+        let target = to.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else if target.file_name() != Some(CACHE_COMPLETE_MARKER.as_ref()) {
+            std::fs::copy(&path, target).map_err(Error::bundle(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// URL of the release archive of `version`, e.g.
+/// `.../download/v0.2.2/webr-0.2.2.tar.gz`.
+fn release_url(version: &str) -> String {
+    format!(
+        "{}/download/{}/webr-{}.tar.gz",
+        WEBR_RELEASES,
+        version,
+        version.trim_start_matches('v')
+    )
+}
+
+/// The version of a local webR runtime, from the `package.json` of the npm
+/// package or the `webr-<version>` name of a release directory or archive.
+fn detect_version(path: &Path) -> Option<String> {
+    let from_package = std::fs::read_to_string(path.join("package.json"))
+        .ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        .and_then(|json| json["version"].as_str().map(normalize_version));
+    from_package.or_else(|| {
+        let name = path.file_name()?.to_str()?;
+        let version = name
+            .strip_prefix("webr-")?
+            .trim_end_matches(".tar.gz")
+            .trim_end_matches(".tgz");
+        version
+            .starts_with(|c: char| c.is_ascii_digit())
+            .then(|| normalize_version(version))
+    })
+}
+
+/// The version of the local webR runtime at `path`. When it can't be told
+/// from the files, `requested` is used, and when both are known they must
+/// match so the bundle isn't checked against the wrong R version.
+pub fn local_version(path: &Path, requested: Option<&str>) -> BundlerResult<String> {
+    let requested = requested.map(normalize_version);
+    match (detect_version(path), requested) {
+        (Some(vendored), Some(requested)) if vendored != requested => {
+            Err(Error::WebRVersionMismatch {
+                path: path.to_path_buf(),
+                vendored,
+                requested,
+            })
+        }
+        (Some(version), _) | (None, Some(version)) => Ok(version),
+        (None, None) => Err(Error::UnknownWebRVersion(path.to_path_buf())),
+    }
+}
+
+/// Fails when `dir` lacks a file every webR distribution has.
+fn check_distribution(dir: &Path) -> BundlerResult<()> {
+    match WEBR_REQUIRED_FILES
+        .iter()
+        .map(|file| dir.join(file))
+        .find(|path| !path.is_file())
+    {
+        Some(missing) => Err(Error::WebRFileNotFound(missing.display().to_string())),
+        None => Ok(()),
+    }
+}
+
+/// The directory of `dir` holding the distribution: `dir` itself or the
+/// directory the release archive wraps it in.
+fn find_distribution(dir: &Path) -> BundlerResult<PathBuf> {
+    if dir.join("webr.mjs").is_file() {
+        return Ok(dir.to_path_buf());
+    }
+    for entry in std::fs::read_dir(dir).map_err(Error::bundle(dir))? {
+        let path = entry.map_err(Error::bundle(dir))?.path();
+        if path.is_dir() && path.join("webr.mjs").is_file() {
+            return Ok(path);
+        }
+    }
+    Err(Error::WebRFileNotFound(
+        dir.join("webr.mjs").display().to_string(),
+    ))
+}
+
+/// Extracts a webR release archive into `target`, with every file of the
+/// distribution.
+fn extract_release(archive: impl Read, target: &Path) -> BundlerResult<()> {
+    let staging = tempfile::tempdir().map_err(Error::bundle(std::env::temp_dir()))?;
+    tar::Archive::new(GzDecoder::new(archive))
+        .unpack(staging.path())
+        .map_err(Error::bundle(staging.path()))?;
+    let dist = find_distribution(staging.path())?;
+    check_distribution(&dist)?;
+    copy_dir(&dist, target)
+}
+
+/// The version of the latest webR release.
+async fn latest_version(client: &HttpClient) -> BundlerResult<String> {
+    #[derive(serde::Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+    let res = client
+        .send(client.get(WEBR_LATEST_RELEASE))
+        .await
+        .map_err(Error::download("webR", WEBR_LATEST_RELEASE))?;
+    let body = client
+        .body(res.error_for_status()?)
+        .await
+        .map_err(Error::download("webR", WEBR_LATEST_RELEASE))?;
+    let release: Release =
+        serde_json::from_slice(&body).map_err(Error::download("webR", WEBR_LATEST_RELEASE))?;
+    Ok(release.tag_name)
+}
+
+/// Downloads the release archive of `version` and extracts it into
+/// `target`. A release that doesn't exist fails with a 404, any other
+/// failure of the server is reported with its status.
+async fn download_webr(client: &HttpClient, version: &str, target: &Path) -> BundlerResult<()> {
+    let url = release_url(version);
+    let what = format!("webR {}", version);
+    let mut res = client
+        .send(client.get(&url))
+        .await
+        .map_err(Error::download(&what, &url))?;
+    match res.status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_FOUND => return Err(Error::WebRFileNotFound(url)),
+        status => {
+            return Err(Error::Download {
+                package: what,
+                url,
+                status: Some(status.as_u16()),
+                reason: status.to_string(),
+            })
+        }
+    }
+    info!("Downloading {}...", url.green().bold());
+    let mut archive = tempfile::tempfile().map_err(Error::download(&what, &url))?;
+    while let Some(chunk) = client
+        .chunk(&mut res)
+        .await
+        .map_err(Error::download(&what, &url))?
+    {
+        archive
+            .write_all(&chunk)
+            .map_err(Error::download(&what, &url))?;
+    }
+    archive
+        .seek(SeekFrom::Start(0))
+        .map_err(Error::download(&what, &url))?;
+    extract_release(BufReader::new(archive), target)
+}
+
+/// Copies the webR runtime into `outdir/webr`.
+///
+/// `spec` is either a path to a local directory with the webR distribution
+/// files, a path to a downloaded release archive, or a webR version (e.g.
+/// `v0.2.1` or `latest`) whose release archive is downloaded. Downloaded
+/// versions are cached so later builds can work offline.
+///
+/// Returns the version of the downloaded release, with `latest` resolved to
+/// its tag, or `None` when the runtime was copied from a local one.
+pub async fn vendor_webr(
+    client: &HttpClient,
+    outdir: impl AsRef<Path>,
+    spec: &str,
+) -> BundlerResult<Option<String>> {
+    let target = outdir.as_ref().join(VENDOR_DIR);
+    let local = Path::new(spec);
+    if local.is_dir() {
+        info!(
+            "Copying webR runtime from {}...",
+            local.display().to_string().green().bold()
+        );
+        check_distribution(local)?;
+        copy_dir(local, &target)?;
+        return Ok(None);
+    }
+    if local.is_file() {
+        info!(
+            "Extracting webR runtime from {}...",
+            local.display().to_string().green().bold()
+        );
+        let archive = std::fs::File::open(local).map_err(Error::bundle(local))?;
+        extract_release(BufReader::new(archive), &target)?;
+        return Ok(None);
+    }
+    let mut version = normalize_version(spec);
+    if version == "latest" {
+        version = latest_version(client).await?;
+    }
+    match cache_dir(&version) {
+        Some(cache) => {
+            if !cache.join(CACHE_COMPLETE_MARKER).exists() {
+                info!("Downloading webR {}...", version.green().bold());
+                download_webr(client, &version, &cache).await?;
+                let marker = cache.join(CACHE_COMPLETE_MARKER);
+                std::fs::write(&marker, "").map_err(Error::bundle(&marker))?;
+            } else {
+                info!("Using cached webR {}...", version.green().bold());
+            }
+            copy_dir(&cache, &target)?;
+        }
+        None => {
+            info!("Downloading webR {}...", version.green().bold());
+            download_webr(client, &version, &target).await?;
+        }
+    }
+    Ok(Some(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_version() {
+        assert_eq!(normalize_version("0.2.1"), "v0.2.1");
+        assert_eq!(normalize_version("v0.2.1"), "v0.2.1");
+        assert_eq!(normalize_version("latest"), "latest");
+    }
+
+    #[test]
+    fn test_local_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let npm = dir.path().join("dist");
+        std::fs::create_dir(&npm).unwrap();
+        std::fs::write(npm.join("package.json"), r#"{"version": "0.3.1"}"#).unwrap();
+        assert_eq!(local_version(&npm, None).unwrap(), "v0.3.1");
+        assert_eq!(local_version(&npm, Some("0.3.1")).unwrap(), "v0.3.1");
+        assert!(matches!(
+            local_version(&npm, Some("v0.2.2")),
+            Err(Error::WebRVersionMismatch { vendored, .. }) if vendored == "v0.3.1"
+        ));
+        let release = dir.path().join("webr-0.2.2.tar.gz");
+        assert_eq!(local_version(&release, None).unwrap(), "v0.2.2");
+        let unknown = dir.path().join("runtime");
+        assert_eq!(local_version(&unknown, Some("0.2.1")).unwrap(), "v0.2.1");
+        assert!(matches!(
+            local_version(&unknown, None),
+            Err(Error::UnknownWebRVersion(_))
+        ));
+    }
+
+    /// Writes a release archive laid out like `webr-0.2.2.tar.gz`, leaving
+    /// out `missing`.
+    fn write_release(path: &Path, missing: Option<&str>) {
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        for file in [
+            "webr.mjs",
+            "webr-worker.js",
+            "webr-serviceworker.js",
+            "R.bin.js",
+            "R.bin.wasm",
+            "R.bin.data",
+            "libRblas.so",
+            "libRlapack.so",
+            "vfs/usr/lib/R/library/base/R/base.rdb",
+            "vfs/usr/lib/R/doc/html/R.css",
+        ] {
+            if Some(file) == missing {
+                continue;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, format!("webr-0.2.2/{}", file), file.as_bytes())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_release_url() {
+        assert_eq!(
+            release_url("v0.2.2"),
+            "https://github.com/r-wasm/webr/releases/download/v0.2.2/webr-0.2.2.tar.gz"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vendor_webr_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let release = dir.path().join("webr-0.2.2.tar.gz");
+        write_release(&release, None);
+        let client = HttpClient::new(
+            &clap::Parser::parse_from(["webr-bundle"]),
+            &Default::default(),
+            ".",
+        )
+        .unwrap();
+        let outdir = dir.path().join("dist");
+        // Local runtimes have their version read before they are vendored
+        assert_eq!(
+            vendor_webr(&client, &outdir, release.to_str().unwrap())
+                .await
+                .unwrap(),
+            None
+        );
+        let webr = outdir.join(VENDOR_DIR);
+        for file in [
+            "R.bin.wasm",
+            "R.bin.js",
+            "vfs/usr/lib/R/library/base/R/base.rdb",
+        ] {
+            assert!(webr.join(file).is_file(), "{} is missing", file);
+        }
+        // The vendored runtime can be copied again from its directory
+        let copy = dir.path().join("copy");
+        vendor_webr(&client, &copy, webr.to_str().unwrap())
+            .await
+            .unwrap();
+        assert!(copy
+            .join(VENDOR_DIR)
+            .join("vfs/usr/lib/R/doc/html/R.css")
+            .is_file());
+
+        write_release(&release, Some("R.bin.wasm"));
+        assert!(matches!(
+            vendor_webr(&client, dir.path().join("broken"), release.to_str().unwrap()).await,
+            Err(Error::WebRFileNotFound(path)) if path.ends_with("R.bin.wasm")
+        ));
+    }
+
+    #[test]
+    fn test_check_mount_support() {
+        assert!(check_mount_support(DEFAULT_WEBR_VERSION, "--library-image").is_err());
+        assert!(check_mount_support("v0.3.0-rc.1", "--library-image").is_ok());
+        assert!(check_mount_support("v0.4.2", "--packaging image").is_ok());
+        assert!(check_mount_support("latest", "--packaging image").is_ok());
+        assert!(check_mount_support("nightly", "--packaging image").is_ok());
+    }
+
+    #[test]
+    fn test_check_compatibility() {
+        assert!(check_compatibility("v0.2.2", "4.3").is_ok());
+        assert!(check_compatibility("v0.4.0", "4.4").is_ok());
+        assert!(matches!(
+            check_compatibility("v0.4.0", "4.3"),
+            Err(Error::IncompatibleWebR {
+                expected: "4.4",
+                ..
+            })
+        ));
+        assert!(check_compatibility("v0.2.2", "4.4").is_err());
+        // Releases missing from R_CONTRIB_VERSIONS, like this one which
+        // must not be taken for a v0.2 release, only produce a warning
+        assert!(r_contrib_version("v0.20.0").is_none());
+        assert!(check_compatibility("v0.20.0", "4.3").is_ok());
+        assert!(check_compatibility("latest", "4.3").is_ok());
+    }
+}